@@ -2,10 +2,17 @@ use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::ban::BAN_DURATION;
 use crate::generator::Generator;
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::crypto::hash::Hashable;
 
 use log::info;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -17,6 +24,8 @@ pub struct Server {
     miner: MinerHandle,
     generator: Generator,
     network: NetworkServerHandle,
+    bc: Arc<Mutex<Blockchain>>,
+    mem_pool: Arc<Mutex<Mempool>>,
 }
 
 #[derive(Serialize)]
@@ -38,12 +47,23 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         generator: &Generator,
         network: &NetworkServerHandle,
+        bc: &Arc<Mutex<Blockchain>>,
+        mem_pool: &Arc<Mutex<Mempool>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -51,12 +71,16 @@ impl Server {
             miner: miner.clone(),
             generator: generator.clone(),
             network: network.clone(),
+            bc: Arc::clone(bc),
+            mem_pool: Arc::clone(mem_pool),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let generator = server.generator.clone();
+                let bc = Arc::clone(&server.bc);
+                let mem_pool = Arc::clone(&server.mem_pool);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -100,6 +124,46 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/bans" => {
+                            respond_json!(req, network.bans());
+                        }
+                        "/network/ban" | "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            let ip = match ip.parse::<IpAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing ip: {}", e));
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/unban" {
+                                network.unban(ip);
+                                respond_result!(req, true, "ok");
+                                return;
+                            }
+                            let duration = match params.get("duration").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing duration: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => BAN_DURATION,
+                            };
+                            network.ban(ip, duration);
+                            respond_result!(req, true, "ok");
+                        }
                         "/trans/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -128,6 +192,33 @@ impl Server {
                             generator.exit();
                             respond_result!(req, true, "ok");
                         }
+                        "/mempool/stats" => {
+                            respond_json!(req, mem_pool.lock().unwrap().stats());
+                        }
+                        "/blockchain/snapshot" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let path = match params.get("path") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing path");
+                                    return;
+                                }
+                            };
+                            let snapshot = bc.lock().unwrap().snapshot();
+                            match snapshot.save(Path::new(path)) {
+                                Ok(()) => respond_result!(
+                                    req,
+                                    true,
+                                    format!("{}:{}", snapshot.base(), snapshot.hash())
+                                ),
+                                Err(e) => respond_result!(
+                                    req,
+                                    false,
+                                    format!("error writing snapshot: {}", e)
+                                ),
+                            }
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
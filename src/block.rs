@@ -1,5 +1,6 @@
-use crate::crypto::{merkle::MerkleTree, hash::{H256, Hashable}};
-use crate::signedtrans::{SignedTrans, generate_random_signedtrans};
+use crate::crypto::{merkle::MerkleTree, hash::{H256, U256, Hashable, generate_rand_hash160}};
+use crate::signedtrans::SignedTrans;
+use crate::transaction::coin_base;
 use serde::{Serialize, Deserialize};
 use rand::Rng;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -30,6 +31,20 @@ impl Header {
     pub fn get_create_time(&self) -> u128 {
         self.timestamp
     }
+
+    pub fn get_merkle_root(&self) -> H256 {
+        self.merkle_root
+    }
+
+    /// Expected number of hashes to meet the target, 2^256 / (target + 1).
+    /// Computed as (2^256 - target - 1) / (target + 1) + 1, which fits in 256 bits.
+    pub fn work(&self) -> U256 {
+        let target = U256::from(self.difficulty);
+        if target == U256::MAX {
+            return U256::ONE;
+        }
+        !target / (target + U256::ONE) + U256::ONE
+    }
 }
 
 impl Hashable for Block {
@@ -38,6 +53,13 @@ impl Hashable for Block {
     }
 }
 
+/// Merkle root of a block content. Leaves commit to the signatures too, not only to the txids,
+/// so the signatures of a block can't be swapped without changing its hash.
+pub fn merkle_root(content: &[SignedTrans]) -> H256 {
+    let leaves: Vec<H256> = content.iter().map(|tx| tx.witness_hash()).collect();
+    MerkleTree::new(&leaves).root()
+}
+
 impl Block {
     pub fn new(parent: H256, nonce:u32, difficulty:H256, timestamp:u128,
                merkle_root:H256, content:Vec<SignedTrans>) -> Block {
@@ -47,6 +69,11 @@ impl Block {
     pub fn get_difficulty(&self) -> H256 {
         self.header.difficulty
     }
+
+    /// Serialized size in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(&self).unwrap() as usize
+    }
 }
 
 pub fn generate_random_block(parent: &H256) -> Block {
@@ -58,15 +85,11 @@ pub fn generate_random_block(parent: &H256) -> Block {
     // sr.fill(&mut result).unwrap(); // random difficulty
     result[0] = 1;
 
-    // init random transactions
-    let trans:Vec<SignedTrans> = vec![
-        generate_random_signedtrans().into(),
-        generate_random_signedtrans().into(),
-        generate_random_signedtrans().into()
-    ];
+    // only a coinbase claiming nothing: random transactions would not be valid against the parent's state
+    let coinbase = coin_base(&generate_rand_hash160(), parent, 0);
+    let trans:Vec<SignedTrans> = vec![SignedTrans::coinbase(coinbase)];
 
-    let merkle_tree = MerkleTree::new(&trans);
-    let root = merkle_tree.root();
+    let root = merkle_root(&trans);
 
     let blk = Block::new(
         H256::from(parent_array),
@@ -91,8 +114,7 @@ pub fn generate_genesis_block(parent: &H256) -> Block {
     result[1] = 16;
 
     let data:Vec<SignedTrans> = Vec::new();
-    let merkle_tree = MerkleTree::new(&data);
-    let root = merkle_tree.root();
+    let root = merkle_root(&data);
 
     let blk = Block::new(
         H256::from(parent_array),
@@ -109,5 +131,45 @@ pub fn generate_genesis_block(parent: &H256) -> Block {
 
 #[cfg(any(test, test_utilities))]
 pub mod test {
+    use super::*;
+
+    /// Build a block on top of `parent` carrying the given transactions,
+    /// preceded by an empty coinbase unless the first one is already a coinbase
+    pub fn generate_block(parent: &H256, content: Vec<SignedTrans>) -> Block {
+        let mut blk = generate_random_block(parent);
+        if content.first().is_some_and(|tx| tx.transaction.is_coinbase()) {
+            set_content(&mut blk, content);
+        } else {
+            let mut with_coinbase = blk.content.clone();
+            with_coinbase.extend(content);
+            set_content(&mut blk, with_coinbase);
+        }
+        blk
+    }
+
+    /// Replace the content of a block, keeping the merkle root in sync
+    pub fn set_content(block: &mut Block, content: Vec<SignedTrans>) {
+        block.header.merkle_root = merkle_root(&content);
+        block.content = content;
+    }
+
+    /// Search a nonce that satisfies the block's difficulty
+    pub fn mine(block: &mut Block) {
+        block.header.nonce = 0;
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+    }
+
+    /// Build and mine a valid block on top of `parent` carrying the given transactions
+    pub fn generate_mined_block(parent: &Block, content: Vec<SignedTrans>) -> Block {
+        let mut blk = generate_block(&parent.hash(), content);
+        blk.header.difficulty = parent.header.difficulty;
+        mine(&mut blk);
+        blk
+    }
 
+    pub fn set_timestamp(block: &mut Block, timestamp: u128) {
+        block.header.timestamp = timestamp;
+    }
 }
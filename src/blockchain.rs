@@ -1,61 +1,261 @@
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
 use std::time::SystemTime;
-use crate::block::Block;
-use crate::crypto::hash::{H160, H256, Hashable};
+use log::{error, warn};
+use crate::block::{Block, Header};
+use crate::crypto::hash::{H160, H256, U256, Hashable};
 use crate::block::generate_genesis_block;
-use crate::signedtrans::SignedTrans;
-use crate::transaction::Transaction;
+use crate::transaction::{Amount, OutPoint, Output};
 use crate::state::State;
+use crate::params::ChainParams;
+use crate::validation::BlockError;
+use crate::store::BlockStore;
+use crate::snapshot::{Snapshot, SnapshotError};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Blockchain {
     pub blockchain: HashMap<H256,Block>, //blocks in the blockchain
-    pub blocks: HashMap<H256,(Block,u32)>, //all blocks in the network, u32 refers to the height of that block
+    pub blocks: HashMap<H256,(Block,u32,U256)>, //all blocks in the network, with their height and the cumulative work of their chain
+    pub headers: HashMap<H256,(Header,u32,U256)>, //headers of the blocks and of the blocks still to download, same height and work
+    best_header: H256, //the header with the most work, blocks are downloaded towards it
+    genesis: H256, //identifies the network, also when the chain started from a snapshot
+    block_state: HashMap<H256,State>, //ledger state after executing each block in blocks
     height: u32,
     tip: H256,
     block_num:u128,
     pub current_state: State,
-    pub address_list: Vec<H160>
+    pub address_list: Vec<H160>,
+    pub params: ChainParams,
+    store: Option<BlockStore>, //inserted blocks are written there when the chain lives on disk
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_params(ChainParams::default())
+    }
+
+    /// Create a new blockchain with the given consensus parameters
+    pub fn with_params(params: ChainParams) -> Self {
         let mut blocks = HashMap::new();
         let mut blockchain = HashMap::new();
+        let mut block_state = HashMap::new();
+        let mut headers = HashMap::new();
 
         let genesis = generate_genesis_block(&H256::from([0u8; 32]));
 
         let hashvalue = genesis.hash();
-        blocks.insert(hashvalue,(genesis.clone(),0));
+        blocks.insert(hashvalue,(genesis.clone(),0,genesis.header.work()));
         blockchain.insert(hashvalue,genesis.clone());
+        block_state.insert(hashvalue, State::new());
+        headers.insert(hashvalue, (genesis.header.clone(), 0, genesis.header.work()));
         Blockchain{
             blockchain,
             blocks,
+            headers,
+            best_header: hashvalue,
+            genesis: hashvalue,
+            block_state,
             height: 0,
             tip: hashvalue,
             block_num: 0,
             current_state: State::new(),
             address_list: Vec::new(),
+            params,
+            store: None,
+        }
+    }
+
+    /// Open the blockchain stored in `dir`, replaying every stored block to rebuild the block tree
+    /// and the states. Blocks inserted afterwards are appended to the store.
+    pub fn open(params: ChainParams, dir: &Path) -> io::Result<Self> {
+        Self::with_params(params).load_store(dir)
+    }
+
+    /// Replay the blocks stored in `dir` on top of this chain, then keep storing inserted blocks there.
+    /// The blocks were validated before they were stored, so their heights and work come from the
+    /// store index and only their transactions are applied to rebuild the states.
+    pub fn load_store(mut self, dir: &Path) -> io::Result<Self> {
+        let (store, blocks) = BlockStore::open(dir)?;
+        let mut tip_work = self.blocks[&self.tip].2;
+        for block in blocks {
+            let hash = block.hash();
+            if self.blocks.contains_key(&hash) {
+                continue;
+            }
+            let pos = store.get_pos(&hash).unwrap();
+            match self.blocks.get(&block.header.parent) {
+                Some((_, height, work)) if height + 1 == pos.height && work.saturating_add(block.header.work()) == pos.work => {}
+                _ => {
+                    warn!("Skipping stored block {:?} (blk{:05}.dat at {}): it doesn't extend a known block", hash, pos.file, pos.offset);
+                    continue;
+                }
+            }
+            // the coinbase outputs are added last, as when the block was connected
+            let mut state = self.block_state[&block.header.parent].clone();
+            for tx in block.content.iter().skip(1) {
+                state.apply(tx);
+            }
+            if let Some(coinbase) = block.content.first() {
+                state.apply(coinbase);
+            }
+            self.block_state.insert(hash, state);
+            self.add_header(&block.header, pos.height, pos.work);
+            self.blocks.insert(hash, (block, pos.height, pos.work));
+            self.block_num += 1;
+            if pos.work > tip_work || (pos.work == tip_work && hash < self.tip) {
+                self.tip = hash;
+                tip_work = pos.work;
+            }
+        }
+        //the longest chain ends at the block with the most work
+        self.blockchain.clear();
+        let mut current = self.tip;
+        while let Some((block, _, _)) = self.blocks.get(&current) {
+            self.blockchain.insert(current, block.clone());
+            current = block.header.parent;
         }
+        self.height = self.blocks[&self.tip].1;
+        self.current_state = self.block_state[&self.tip].clone();
+        self.store = Some(store);
+        Ok(self)
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) -> u128 {
+    /// Start from a UTXO snapshot instead of the genesis, once its hash is checked against the
+    /// one the chain parameters expect for its block. Blocks before the snapshot are never known.
+    pub fn from_snapshot(params: ChainParams, snapshot: &Snapshot) -> Result<Self, SnapshotError> {
+        let base = snapshot.base();
+        match params.assume_utxo {
+            Some((block, _)) if block != base => return Err(SnapshotError::UnknownBase),
+            Some((_, hash)) if hash != snapshot.hash() => return Err(SnapshotError::HashMismatch),
+            Some(_) => {}
+            None => return Err(SnapshotError::UnknownBase),
+        }
+        let linked = snapshot.blocks.windows(2).all(|pair| pair[1].header.parent == pair[0].hash());
+        if snapshot.blocks.is_empty() || !linked || snapshot.blocks.len() as u32 > snapshot.height + 1 {
+            return Err(SnapshotError::BrokenChain);
+        }
+
+        let mut state = State::new();
+        state.map.extend(snapshot.utxos.iter().cloned());
+        let mut chain = Self::with_params(params);
+        chain.blocks.clear();
+        chain.blockchain.clear();
+        chain.block_state.clear();
+        chain.headers.clear();
+        //heights and cumulative work of the ancestors, walking down from the base block
+        let mut height = snapshot.height;
+        let mut work = U256::from(snapshot.work);
+        for block in snapshot.blocks.iter().rev() {
+            chain.blocks.insert(block.hash(), (block.clone(), height, work));
+            chain.blockchain.insert(block.hash(), block.clone());
+            chain.headers.insert(block.hash(), (block.header.clone(), height, work));
+            height = height.saturating_sub(1);
+            work = work.checked_sub(block.header.work()).unwrap_or(U256::ZERO);
+        }
+        chain.block_state.insert(base, state.clone());
+        chain.current_state = state;
+        chain.tip = base;
+        chain.best_header = base;
+        chain.height = snapshot.height;
+        Ok(chain)
+    }
+
+    /// UTXO snapshot of the tip, with the blocks a node starting from it needs to retarget
+    pub fn snapshot(&self) -> Snapshot {
+        let (_, height, work) = self.blocks.get(&self.tip).unwrap();
+        let mut blocks = Vec::new();
+        let mut current = self.tip;
+        while let Some((block, _, _)) = self.blocks.get(&current) {
+            blocks.push(block.clone());
+            if blocks.len() as u32 >= self.params.retarget_interval.max(1) {
+                break;
+            }
+            current = block.header.parent;
+        }
+        blocks.reverse();
+        let mut utxos: Vec<(OutPoint, Output)> = self.current_state.map.iter()
+            .map(|(outpoint, out)| (*outpoint, out.clone()))
+            .collect();
+        utxos.sort_by_key(|(outpoint, _)| (outpoint.txid, outpoint.index));
+        Snapshot{ height: *height, work: (*work).into(), blocks, utxos }
+    }
+
+    /// Validate and execute the transactions of a block in order, on top of its parent's state.
+    /// The first transaction must be the only coinbase, claiming at most the subsidy plus the fees.
+    /// Returns the state after the block.
+    pub fn connect_block(&self, block: &Block) -> Result<State, BlockError> {
+        let parent = &block.header.parent;
+        let mut state = self.block_state.get(parent)
+            .ok_or(BlockError::UnknownParent)?
+            .clone();
+        let height = self.blocks.get(parent).unwrap().1 + 1;
+
+        let (coinbase, content) = match block.content.split_first() {
+            Some((coinbase, content)) if coinbase.transaction.is_coinbase() => (coinbase, content),
+            _ => return Err(BlockError::MissingCoinbase),
+        };
+        if coinbase.transaction.inputs[0].previous_hash != *parent {
+            return Err(BlockError::BadCoinbase);
+        }
+
+        let mut fees: Amount = 0;
+        for tx in content.iter() {
+            if tx.transaction.is_coinbase() {
+                return Err(BlockError::MultipleCoinbase);
+            }
+            let fee = state.check(tx).map_err(|e| BlockError::InvalidTransaction(tx.hash(), e))?;
+            fees = fees.checked_add(fee).ok_or(BlockError::CoinbaseTooLarge)?;
+            state.apply(tx);
+        }
+
+        // outputs of the coinbase are added last, they can't be spent inside their own block
+        let allowed = self.params.subsidy(height).checked_add(fees).ok_or(BlockError::CoinbaseTooLarge)?;
+        match coinbase.transaction.output_val() {
+            Some(value) if value <= allowed => {}
+            _ => return Err(BlockError::CoinbaseTooLarge),
+        }
+        state.apply(coinbase);
+        Ok(state)
+    }
+
+    /// Insert a block into blockchain, fails if its transactions can't be executed on its parent
+    /// or it can't be written to the store
+    pub fn insert(&mut self, block: &Block) -> Result<u128, BlockError> {
         let newblock = block.clone();
         let parent = &newblock.header.parent;
-        let nheight;
+        let (_, parent_height, parent_work) = self.blocks.get(parent).ok_or(BlockError::UnknownParent)?;
+        let nheight = parent_height + 1;
+        let nwork = parent_work.saturating_add(newblock.header.work());
+        let tip_work = self.blocks.get(&self.tip).unwrap().2;
+
+        //every block in blocks keeps its own copy of the state
+        let state = match self.connect_block(block) {
+            Ok(state) => state,
+            Err(e) => {
+                self.discard_header(&newblock.hash());
+                return Err(e);
+            }
+        };
+
+        //write the block to disk first, so a crash never loses a block we already built on
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(block, nheight, nwork) {
+                error!("Failed to store block {:?}: {}", newblock.hash(), e);
+                return Err(BlockError::Storage(e.to_string()));
+            }
+        }
+        self.block_state.insert(newblock.hash(), state);
 
         //The parent of the newly inserted block is the tip of the blockchain, insert new block directly
         if parent == &self.tip {
             self.tip = newblock.hash();
-            self.height = self.height+1;
-            nheight = self.height;
+            self.height = nheight;
             self.blockchain.insert(self.tip, block.clone());
-        //after insert this block, another branch becomes the longest chain
-        } else if self.height < self.blocks.get(&parent).unwrap().1 +1 {
-            nheight = self.blocks.get(&parent).unwrap().1 +1;
+        //after insert this block, another branch has the most work, on a tie the lowest hash wins
+        } else if nwork > tip_work || (nwork == tip_work && newblock.hash() < self.tip) {
             self.height = nheight;
             //update blockchain
             let mut new_chain: Vec<H256> = Vec::new(); //the last one element's parent is in the blockchain 
@@ -79,57 +279,187 @@ impl Blockchain {
             }
             self.tip = newblock.hash();
             self.blockchain.insert(self.tip, block.clone());
-        } else {
-            //the blockchain doestn't change, only insert new block into blocks
-            nheight = self.blocks.get(&parent).unwrap().1 + 1;
         }
-        self.blocks.insert(newblock.hash(), (block.clone(), nheight));
+        //otherwise the blockchain doesn't change, only insert new block into blocks
+        self.blocks.insert(newblock.hash(), (block.clone(), nheight, nwork));
+        self.add_header(&block.header, nheight, nwork);
         self.block_num += 1;
+        //rewind or replay the ledger by switching to the state of the (possibly new) tip
+        self.current_state = self.block_state.get(&self.tip).unwrap().clone();
 
         let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
         println!("{:?} insert {:?} at {:?}, bc height:{:?}", ts, block.hash(), nheight, self.height);
 
-        ts.as_millis() - block.header.get_create_time()
+        Ok(ts.as_millis().saturating_sub(block.header.get_create_time()))
+    }
+
+    /// Insert a header whose block isn't downloaded yet, returns false if it was already known.
+    /// The header must have been validated.
+    pub fn insert_header(&mut self, header: &Header) -> Result<bool, BlockError> {
+        if self.headers.contains_key(&header.hash()) {
+            return Ok(false);
+        }
+        let (_, parent_height, parent_work) = self.headers.get(&header.parent).ok_or(BlockError::UnknownParent)?;
+        let (height, work) = (parent_height + 1, parent_work.saturating_add(header.work()));
+        self.add_header(header, height, work);
+        Ok(true)
+    }
+
+    fn add_header(&mut self, header: &Header, height: u32, work: U256) {
+        let hash = header.hash();
+        self.headers.entry(hash).or_insert_with(|| (header.clone(), height, work));
+        if self.beats_best_header(&hash, work) {
+            self.best_header = hash;
+        }
+    }
+
+    fn beats_best_header(&self, hash: &H256, work: U256) -> bool {
+        let best_work = self.headers[&self.best_header].2;
+        work > best_work || (work == best_work && *hash < self.best_header)
+    }
+
+    /// A block failed validation: if it is invalid, its header and the headers built on it are
+    /// forgotten, so the download moves to the best chain left
+    pub fn reject(&mut self, hash: &H256, error: &BlockError) {
+        if error.is_invalid() {
+            self.discard_header(hash);
+        }
+    }
+
+    /// Forget the header of an invalid block and every header built on it,
+    /// so the download moves to the best chain left
+    fn discard_header(&mut self, hash: &H256) {
+        if self.blocks.contains_key(hash) || self.headers.remove(hash).is_none() {
+            return;
+        }
+        let mut discarded: HashSet<H256> = HashSet::new();
+        discarded.insert(*hash);
+        loop {
+            let children: Vec<H256> = self.headers.iter()
+                .filter(|(_, (header, _, _))| discarded.contains(&header.parent))
+                .map(|(child, _)| *child)
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            for child in children {
+                self.headers.remove(&child);
+                discarded.insert(child);
+            }
+        }
+        self.best_header = self.tip;
+        let candidates: Vec<(H256, U256)> = self.headers.iter().map(|(hash, (_, _, work))| (*hash, *work)).collect();
+        for (hash, work) in candidates {
+            if self.beats_best_header(&hash, work) {
+                self.best_header = hash;
+            }
+        }
+    }
+
+    pub fn genesis(&self) -> H256 {
+        self.genesis
     }
 
-    pub fn update_state(&mut self, sigtrans:&SignedTrans, memp_size:usize) {
-        let transaction = sigtrans.clone().transaction;
-        // let hash = block.hash();
-        // self.block_state.insert(hash, State::new());
-        // return
-        let mut st = self.current_state.clone().map;
-        let mut sig = self.current_state.clone().sig;
-        sig.insert(sigtrans.transaction.id, sigtrans.clone());
-        let mut collection = HashMap::new();
-        let vec_in = transaction.inputs.clone();
-        for tx_in in vec_in {
-            collection.insert(tx_in.previous_hash,tx_in);
+    pub fn best_header(&self) -> H256 {
+        self.best_header
+    }
+
+    /// Hashes on the best header chain, the last 10 then exponentially sparser,
+    /// always ending with the oldest header known. A peer finds where our chains fork with it.
+    pub fn block_locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut current = self.best_header;
+        let mut step = 1;
+        loop {
+            locator.push(current);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            //walk back `step` headers, stopping at the oldest one
+            let mut next = current;
+            for _ in 0..step {
+                let parent = self.headers[&next].0.parent;
+                if !self.headers.contains_key(&parent) {
+                    break;
+                }
+                next = parent;
+            }
+            if next == current {
+                return locator;
+            }
+            current = next;
         }
-        for (hash,_) in st.clone() {
-            if collection.contains_key(&hash){
-                st.remove(&hash);
-                sig.remove(&hash);
+    }
+
+    /// Headers of the longest chain after the first locator hash on it, at most `max` of them.
+    /// Without a common block they start at the oldest block of the chain.
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let fork = locator.iter().find(|hash| self.blockchain.contains_key(hash));
+        let mut chain = Vec::new();
+        let mut current = self.tip;
+        while let Some(block) = self.blockchain.get(&current) {
+            if Some(&current) == fork {
+                break;
             }
+            chain.push(block.header.clone());
+            current = block.header.parent;
         }
-        for out in transaction.clone().outputs {
-            st.insert(transaction.id,out.clone());
+        chain.reverse();
+        chain.truncate(max);
+        chain
+    }
+
+    /// Blocks on the best header chain that aren't downloaded yet, the first `max` of them by height
+    pub fn missing_blocks(&self, max: usize) -> Vec<H256> {
+        let mut missing = Vec::new();
+        let mut current = self.best_header;
+        while !self.blocks.contains_key(&current) {
+            match self.headers.get(&current) {
+                Some((header, _, _)) => {
+                    missing.push(current);
+                    current = header.parent;
+                }
+                None => break,
+            }
         }
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
 
-        self.current_state = State{map:st, sig};
-        self.print_state(memp_size);
+    /// Get the ledger state after executing the given block
+    pub fn get_state(&self, hash: &H256) -> Option<&State> {
+        self.block_state.get(hash)
     }
 
     pub fn print_state(&self, memp_size:usize) {
-        let mut balance:HashMap<H160, u8> = HashMap::new();
-        for account in self.clone().address_list {
-            balance.insert(account, 0);
+        let mut balance:HashMap<H160, Amount> = HashMap::new();
+        for account in self.address_list.iter() {
+            balance.insert(*account, 0);
         }
-        for (_, out) in self.clone().current_state.map {
-            *balance.get_mut(&out.address).unwrap() += out.balance;
+        for out in self.current_state.map.values() {
+            let total = balance.entry(out.address).or_insert(0);
+            *total = total.saturating_add(out.balance);
         }
         println!("state:{:?} mempool size:{:?}", balance, memp_size);
     }
 
+    /// Blocks of the branch ending at `old_tip` that are no longer on the main chain, oldest first
+    pub fn disconnected_since(&self, old_tip: &H256) -> Vec<Block> {
+        let mut disconnected = Vec::new();
+        let mut hash = *old_tip;
+        while !self.blockchain.contains_key(&hash) {
+            let block = match self.blocks.get(&hash) {
+                Some((block, _, _)) => block,
+                None => break,
+            };
+            hash = block.header.parent;
+            disconnected.push(block.clone());
+        }
+        disconnected.reverse();
+        disconnected
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.tip
@@ -139,6 +469,30 @@ impl Blockchain {
         self.blockchain.get(self.tip.borrow()).unwrap().get_difficulty()
     }
 
+    /// Target a child of `parent` must meet. It is the parent's, except every `retarget_interval`
+    /// blocks where it is scaled by the time the blocks since the last retarget took.
+    /// The genesis timestamp is not a real one, so the window never reaches back to it.
+    /// Only headers are needed, so it also checks headers whose blocks aren't downloaded yet.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let (parent_header, parent_height, _) = self.headers.get(parent).unwrap();
+        let height = parent_height + 1;
+        let interval = self.params.retarget_interval.max(1);
+        if height % interval != 0 {
+            return parent_header.difficulty;
+        }
+        let first_height = height.saturating_sub(interval).max(1);
+        if first_height >= *parent_height {
+            return parent_header.difficulty;
+        }
+        let mut first = parent_header;
+        for _ in first_height..*parent_height {
+            first = &self.headers.get(&first.parent).unwrap().0;
+        }
+        let actual = parent_header.get_create_time().saturating_sub(first.get_create_time());
+        let expected = (parent_height - first_height) as u128 * self.params.target_block_time as u128;
+        self.params.retarget(parent_header.difficulty, actual, expected)
+    }
+
     pub fn get_length(&self) -> u32 {
         self.height
     }
@@ -156,9 +510,12 @@ impl Blockchain {
     #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut block_hash: Vec<H256> = Vec::new();
-        for block in self.blockchain.iter() {
-            block_hash.push(*block.0);
+        let mut current = self.tip;
+        while let Some(block) = self.blockchain.get(&current) {
+            block_hash.push(current);
+            current = block.header.parent;
         }
+        block_hash.reverse();
         block_hash
     }
 }
@@ -168,13 +525,23 @@ mod tests {
     use super::*;
     use crate::crypto::hash::Hashable;
     use crate::block::generate_random_block;
+    use crate::block::test::{generate_block, generate_mined_block, set_content, set_timestamp};
+    use crate::signedtrans::{SignedTrans, generate_random_signedtrans};
+    use crate::transaction::{Input, OutPoint, Output, Transaction, coin_base};
+    use crate::crypto::hash::generate_rand_hash160;
+    use crate::crypto::key_pair;
+    use crate::store::tests::temp_dir;
+    use crate::crypto::hash::generate_rand_hash256;
+    use crate::mempool::Mempool;
+    use crate::validation::validate_block;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
 
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
 
     }
@@ -202,15 +569,455 @@ mod tests {
         let block3 = generate_random_block(&block2.hash());
         let block4 = generate_random_block(&block.hash());
         let block5 = generate_random_block(&block3.hash());
-        blockchain.insert(&block);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
-        blockchain.insert(&block4);
-        blockchain.insert(&block5);
+        blockchain.insert(&block).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
+        blockchain.insert(&block4).unwrap();
+        blockchain.insert(&block5).unwrap();
         let result = blockchain.all_blocks_in_longest_chain();
         for i in 0..result.len() {
               println!("{}", result[i]);
             }
         assert_eq!(result, vec![ genesis_hash, block2.hash(), block3.hash(), block5.hash()]);
       }
+
+    fn coinbase(parent: &H256, address: H160, value: Amount) -> SignedTrans {
+        SignedTrans::coinbase(coin_base(&address, parent, value))
+    }
+
+    fn outpoint(tx: &SignedTrans) -> OutPoint {
+        OutPoint{ txid: tx.hash(), index: 0 }
+    }
+
+    #[test]
+    fn state_per_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_block(&genesis_hash, vec![]);
+        assert!(blockchain.insert(&block).is_ok());
+        assert!(blockchain.get_state(&genesis_hash).unwrap().map.is_empty());
+        assert!(blockchain.get_state(&block.hash()).unwrap().map.contains_key(&outpoint(&block.content[0])));
+        assert!(blockchain.current_state.map.contains_key(&outpoint(&block.content[0])));
+    }
+
+    #[test]
+    fn reject_invalid_transaction() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx = generate_random_signedtrans();
+        let block = generate_block(&genesis_hash, vec![tx]);
+        assert!(blockchain.insert(&block).is_err());
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert!(blockchain.get_state(&block.hash()).is_none());
+        assert!(blockchain.current_state.map.is_empty());
+    }
+
+    #[test]
+    fn spend_in_next_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let funding = coinbase(&genesis_hash, H160::hash(key.public_key().as_ref()), 10);
+        let block = generate_block(&genesis_hash, vec![funding.clone()]);
+        blockchain.insert(&block).unwrap();
+        let spend = Transaction{
+            inputs: vec![Input{index: 0, previous_hash: funding.hash()}],
+            outputs: vec![Output{balance: 10, address: generate_rand_hash160()}],
+        };
+        let spend = SignedTrans::new(spend, &key);
+        let block2 = generate_block(&block.hash(), vec![spend.clone(), spend.clone()]);
+        assert!(blockchain.insert(&block2).is_err());
+        let block2 = generate_block(&block.hash(), vec![spend.clone()]);
+        assert!(blockchain.insert(&block2).is_ok());
+        assert!(!blockchain.current_state.map.contains_key(&outpoint(&funding)));
+        assert!(blockchain.current_state.map.contains_key(&outpoint(&spend)));
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let subsidy = blockchain.params.subsidy(1);
+        let address = generate_rand_hash160();
+
+        let block = generate_block(&genesis_hash, vec![]);
+        let mut missing = block.clone();
+        set_content(&mut missing, vec![]);
+        assert_eq!(blockchain.insert(&missing), Err(BlockError::MissingCoinbase));
+
+        let mut twice = block.clone();
+        set_content(&mut twice, vec![block.content[0].clone(), coinbase(&genesis_hash, address, 0)]);
+        assert_eq!(blockchain.insert(&twice), Err(BlockError::MultipleCoinbase));
+
+        let other_parent = generate_block(&block.hash(), vec![coinbase(&block.hash(), address, 0)]);
+        let mut moved = block.clone();
+        set_content(&mut moved, other_parent.content.clone());
+        assert_eq!(blockchain.insert(&moved), Err(BlockError::BadCoinbase));
+
+        let too_large = generate_block(&genesis_hash, vec![coinbase(&genesis_hash, address, subsidy + 1)]);
+        assert_eq!(blockchain.insert(&too_large), Err(BlockError::CoinbaseTooLarge));
+
+        let full = generate_block(&genesis_hash, vec![coinbase(&genesis_hash, address, subsidy)]);
+        assert!(blockchain.insert(&full).is_ok());
+    }
+
+    #[test]
+    fn coinbase_collects_fees() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let subsidy = blockchain.params.subsidy(1);
+        let key = key_pair::random();
+        let funding = coinbase(&genesis_hash, H160::hash(key.public_key().as_ref()), subsidy);
+        let block = generate_block(&genesis_hash, vec![funding.clone()]);
+        blockchain.insert(&block).unwrap();
+
+        // pay 3 out of the funding output, leaving 3 as fee
+        let spend = Transaction{
+            inputs: vec![Input{index: 0, previous_hash: funding.hash()}],
+            outputs: vec![Output{balance: subsidy - 3, address: generate_rand_hash160()}],
+        };
+        let spend = SignedTrans::new(spend, &key);
+        let subsidy = blockchain.params.subsidy(2);
+        let address = generate_rand_hash160();
+        let greedy = generate_block(&block.hash(), vec![coinbase(&block.hash(), address, subsidy + 4), spend.clone()]);
+        assert_eq!(blockchain.insert(&greedy), Err(BlockError::CoinbaseTooLarge));
+        let block2 = generate_block(&block.hash(), vec![coinbase(&block.hash(), address, subsidy + 3), spend]);
+        assert!(blockchain.insert(&block2).is_ok());
+    }
+
+    #[test]
+    fn reorg_rewinds_state() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut block = generate_block(&genesis_hash, vec![]);
+        let mut block2 = generate_block(&genesis_hash, vec![]);
+        // on equal work the lowest hash is the tip, so block stays the tip until block3
+        if block2.hash() < block.hash() {
+            std::mem::swap(&mut block, &mut block2);
+        }
+        let block3 = generate_block(&block2.hash(), vec![]);
+        let abandoned = block.content[0].clone();
+        let replayed = block2.content[0].clone();
+        blockchain.insert(&block).unwrap();
+        blockchain.insert(&block2).unwrap();
+        assert!(blockchain.current_state.map.contains_key(&outpoint(&abandoned)));
+        blockchain.insert(&block3).unwrap();
+        assert_eq!(blockchain.tip(), block3.hash());
+        assert!(!blockchain.current_state.map.contains_key(&outpoint(&abandoned)));
+        assert!(blockchain.current_state.map.contains_key(&outpoint(&replayed)));
+    }
+
+    #[test]
+    fn reorg_resurrects_transactions() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let address = H160::hash(key.public_key().as_ref());
+        let pay = |txid: H256| SignedTrans::new(Transaction{
+            inputs: vec![Input{index: 0, previous_hash: txid}],
+            outputs: vec![Output{balance: 10, address: generate_rand_hash160()}],
+        }, &key);
+        let funding = coinbase(&genesis_hash, address, 10);
+        let base = generate_block(&genesis_hash, vec![funding.clone()]);
+        let funding2 = coinbase(&base.hash(), address, 10);
+        let base2 = generate_block(&base.hash(), vec![funding2.clone()]);
+        blockchain.insert(&base).unwrap();
+        blockchain.insert(&base2).unwrap();
+
+        // the abandoned branch spends both outputs, the new one only the second
+        let spend = SignedTrans::new(Transaction{
+            inputs: vec![Input{index: 0, previous_hash: funding.hash()}],
+            outputs: vec![Output{balance: 10, address}],
+        }, &key);
+        let child = pay(spend.hash());
+        let replaced = pay(funding2.hash());
+        let abandoned = generate_block(&base2.hash(), vec![spend.clone(), child.clone(), replaced.clone()]);
+        blockchain.insert(&abandoned).unwrap();
+        let mut mempool = Mempool::new();
+
+        let confirmed = pay(funding2.hash());
+        let block = generate_block(&base2.hash(), vec![confirmed]);
+        let block2 = generate_block(&block.hash(), vec![]);
+        blockchain.insert(&block).unwrap();
+        blockchain.insert(&block2).unwrap();
+        assert_eq!(blockchain.tip(), block2.hash());
+
+        let disconnected = blockchain.disconnected_since(&abandoned.hash());
+        assert_eq!(disconnected.len(), 1);
+        assert_eq!(disconnected[0].hash(), abandoned.hash());
+        assert!(blockchain.disconnected_since(&base2.hash()).is_empty());
+
+        let evicted = mempool.update(&disconnected, &blockchain.current_state);
+        assert_eq!(evicted, vec![replaced.hash()]);
+        assert_eq!(mempool.pool.len(), 2);
+        assert!(mempool.pool.contains_key(&spend.hash()));
+        assert!(mempool.pool.contains_key(&child.hash()));
+    }
+
+    #[test]
+    fn retarget_every_interval() {
+        let params = ChainParams{ retarget_interval: 4, target_block_time: 1000, ..Default::default() };
+        // blocks twice too fast, too fast and too slow beyond the 4x bound
+        for (spacing, mul, div) in [(500, 1, 2), (100, 1, 4), (10_000, 4, 1)] {
+            let mut blockchain = Blockchain::with_params(params.clone());
+            let mut parent = blockchain.tip();
+            for height in 1..4 {
+                assert_eq!(blockchain.next_difficulty(&parent), blockchain.blocks[&parent].0.get_difficulty());
+                let mut block = generate_block(&parent, vec![]);
+                set_timestamp(&mut block, 1_000_000 + height * spacing);
+                blockchain.insert(&block).unwrap();
+                parent = block.hash();
+            }
+            let target = blockchain.blocks[&parent].0.get_difficulty();
+            let expected = U256::from(target) * U256::from(mul as u64) / U256::from(div as u64);
+            assert_eq!(blockchain.next_difficulty(&parent), H256::from(expected));
+        }
+    }
+
+    /// A block on top of `parent` with the given target, insert doesn't check the proof of work
+    fn block_with_difficulty(parent: &H256, difficulty: H256) -> Block {
+        let mut block = generate_block(parent, vec![]);
+        block.header.difficulty = difficulty;
+        block
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy = generate_block(&genesis_hash, vec![]).get_difficulty();
+        let hard = H256::from(U256::from(easy) / U256::from(8u64));
+
+        let mut light = vec![genesis_hash];
+        for _ in 0..2 {
+            let block = block_with_difficulty(light.last().unwrap(), easy);
+            blockchain.insert(&block).unwrap();
+            light.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), light[2]);
+
+        // one block with 8 times the work beats two easy ones
+        let heavy = block_with_difficulty(&genesis_hash, hard);
+        blockchain.insert(&heavy).unwrap();
+        assert_eq!(blockchain.tip(), heavy.hash());
+        assert_eq!(blockchain.get_length(), 1);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, heavy.hash()]);
+        assert!(blockchain.blocks[&heavy.hash()].2 > blockchain.blocks[&light[2]].2);
+
+        // until the light chain has more work again
+        for _ in 0..8 {
+            let block = block_with_difficulty(light.last().unwrap(), easy);
+            blockchain.insert(&block).unwrap();
+            light.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), *light.last().unwrap());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), light);
+    }
+
+    #[test]
+    fn equal_work_lowest_hash() {
+        let genesis_hash = Blockchain::new().tip();
+        let a = generate_block(&genesis_hash, vec![]);
+        let b = generate_block(&genesis_hash, vec![]);
+        let lowest = std::cmp::min(a.hash(), b.hash());
+        for (first, second) in [(&a, &b), (&b, &a)] {
+            let mut blockchain = Blockchain::new();
+            blockchain.insert(first).unwrap();
+            blockchain.insert(second).unwrap();
+            assert_eq!(blockchain.tip(), lowest);
+        }
+    }
+
+    #[test]
+    fn reopen_from_disk() {
+        let dir = temp_dir("chain");
+        let mut blockchain = Blockchain::open(ChainParams::default(), &dir).unwrap();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let funding = coinbase(&genesis_hash, H160::hash(key.public_key().as_ref()), 10);
+        let block = generate_block(&genesis_hash, vec![funding.clone()]);
+        let fork = generate_block(&genesis_hash, vec![]);
+        let spend = Transaction{
+            inputs: vec![Input{index: 0, previous_hash: funding.hash()}],
+            outputs: vec![Output{balance: 10, address: generate_rand_hash160()}],
+        };
+        let block2 = generate_block(&block.hash(), vec![SignedTrans::new(spend, &key)]);
+        for b in [&block, &fork, &block2] {
+            blockchain.insert(b).unwrap();
+        }
+        let state = blockchain.current_state.map.clone();
+        drop(blockchain);
+
+        let reopened = Blockchain::open(ChainParams::default(), &dir).unwrap();
+        assert_eq!(reopened.tip(), block2.hash());
+        assert_eq!(reopened.get_length(), 2);
+        assert_eq!(reopened.blocks.len(), 4);
+        let work = reopened.blocks[&genesis_hash].2 + block.header.work() + block2.header.work();
+        assert_eq!(reopened.blocks[&block2.hash()].2, work);
+        assert_eq!(reopened.current_state.map.len(), state.len());
+        assert!(state.keys().all(|outpoint| reopened.current_state.map.contains_key(outpoint)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A chain of `length` blocks 500ms apart, the first one paying 10 to `key`
+    fn funded_chain(params: ChainParams, key: &Ed25519KeyPair, length: u32) -> (Blockchain, SignedTrans) {
+        let mut blockchain = Blockchain::with_params(params);
+        let mut parent = blockchain.tip();
+        let funding = coinbase(&parent, H160::hash(key.public_key().as_ref()), 10);
+        for height in 1..=length {
+            let content = if height == 1 { vec![funding.clone()] } else { vec![] };
+            let mut block = generate_block(&parent, content);
+            set_timestamp(&mut block, 1_000_000 + height as u128 * 500);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        (blockchain, funding)
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let params = ChainParams{ retarget_interval: 4, target_block_time: 1000, ..Default::default() };
+        let key = key_pair::random();
+        let (blockchain, funding) = funded_chain(params.clone(), &key, 7);
+        let snapshot = blockchain.snapshot();
+        assert_eq!(snapshot.base(), blockchain.tip());
+        assert_eq!(snapshot.height, 7);
+        assert_eq!(snapshot.blocks.len(), 4);
+
+        let path = temp_dir("snapshot").join("utxo.dat");
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.hash(), snapshot.hash());
+
+        let params = ChainParams{ assume_utxo: Some((loaded.base(), loaded.hash())), ..params };
+        let mut imported = Blockchain::from_snapshot(params, &loaded).unwrap();
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(imported.get_length(), 7);
+        assert_eq!(imported.blocks[&imported.tip()].2, blockchain.blocks[&blockchain.tip()].2);
+        assert_eq!(imported.current_state.map.len(), blockchain.current_state.map.len());
+
+        // the next block retargets from blocks that came with the snapshot
+        let tip = imported.tip();
+        assert_ne!(imported.next_difficulty(&tip), imported.blocks[&tip].0.get_difficulty());
+        assert_eq!(imported.next_difficulty(&tip), blockchain.next_difficulty(&tip));
+
+        // and the snapshot outputs can be spent
+        let spend = Transaction{
+            inputs: vec![Input{index: 0, previous_hash: funding.hash()}],
+            outputs: vec![Output{balance: 10, address: generate_rand_hash160()}],
+        };
+        let spend = SignedTrans::new(spend, &key);
+        let block = generate_block(&tip, vec![spend.clone()]);
+        imported.insert(&block).unwrap();
+        assert_eq!(imported.get_length(), 8);
+        assert!(!imported.current_state.map.contains_key(&outpoint(&funding)));
+        assert!(imported.current_state.map.contains_key(&outpoint(&spend)));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn snapshot_rejected() {
+        let params = ChainParams{ retarget_interval: 4, ..Default::default() };
+        let (blockchain, _) = funded_chain(params.clone(), &key_pair::random(), 5);
+        let snapshot = blockchain.snapshot();
+        let with = |assume| ChainParams{ assume_utxo: Some(assume), ..params.clone() };
+
+        assert_eq!(Blockchain::from_snapshot(params.clone(), &snapshot).unwrap_err(), SnapshotError::UnknownBase);
+        let other_block = with((blockchain.blocks[&snapshot.base()].0.header.parent, snapshot.hash()));
+        assert_eq!(Blockchain::from_snapshot(other_block, &snapshot).unwrap_err(), SnapshotError::UnknownBase);
+        let other_hash = with((snapshot.base(), generate_rand_hash256()));
+        assert_eq!(Blockchain::from_snapshot(other_hash, &snapshot).unwrap_err(), SnapshotError::HashMismatch);
+
+        let expected = with((snapshot.base(), snapshot.hash()));
+        let mut tampered = snapshot.clone();
+        tampered.utxos[0].1.balance += 1;
+        assert_eq!(Blockchain::from_snapshot(expected.clone(), &tampered).unwrap_err(), SnapshotError::HashMismatch);
+        let mut broken = snapshot.clone();
+        broken.blocks.remove(1);
+        assert_eq!(Blockchain::from_snapshot(expected.clone(), &broken).unwrap_err(), SnapshotError::BrokenChain);
+        assert!(Blockchain::from_snapshot(expected, &snapshot).is_ok());
+    }
+
+    /// A chain of `length` empty blocks on the genesis
+    fn empty_chain(length: usize) -> (Blockchain, Vec<Block>) {
+        let mut blockchain = Blockchain::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..length {
+            let parent = blocks.last().map(|b| b.hash()).unwrap_or_else(|| blockchain.tip());
+            let block = generate_block(&parent, vec![]);
+            blockchain.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        (blockchain, blocks)
+    }
+
+    #[test]
+    fn headers_then_blocks() {
+        let (source, blocks) = empty_chain(5);
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.block_locator(), vec![genesis_hash]);
+
+        let headers = source.headers_after(&blockchain.block_locator(), 3);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0].hash(), blocks[0].hash());
+        let headers = source.headers_after(&[blocks[1].hash(), genesis_hash], 10);
+        assert_eq!(headers.iter().map(|h| h.hash()).collect::<Vec<_>>(),
+                   blocks[2..].iter().map(|b| b.hash()).collect::<Vec<_>>());
+
+        for header in source.headers_after(&blockchain.block_locator(), 10) {
+            assert_eq!(blockchain.insert_header(&header), Ok(true));
+        }
+        assert_eq!(blockchain.insert_header(&blocks[0].header), Ok(false));
+        assert_eq!(blockchain.best_header(), source.tip());
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert_eq!(blockchain.missing_blocks(2), vec![blocks[0].hash(), blocks[1].hash()]);
+
+        for block in blocks[..3].iter() {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(blockchain.tip(), blocks[2].hash());
+        assert_eq!(blockchain.missing_blocks(10), vec![blocks[3].hash(), blocks[4].hash()]);
+        assert_eq!(blockchain.headers[&source.tip()].2, source.blocks[&source.tip()].2);
+    }
+
+    #[test]
+    fn locator_gets_sparser() {
+        let (blockchain, blocks) = empty_chain(30);
+        let locator = blockchain.block_locator();
+        let hashes: Vec<H256> = blocks.iter().rev().map(|b| b.hash()).collect();
+        // the last ten blocks, then steps of 2, 4 and 8, then the genesis
+        assert_eq!(locator[..10], hashes[..10]);
+        assert_eq!(locator[10..13], [hashes[11], hashes[15], hashes[23]]);
+        assert_eq!(locator[13..], [blockchain.blocks[&blocks[0].hash()].0.header.parent]);
+    }
+
+    #[test]
+    fn invalid_block_drops_its_headers() {
+        let (source, blocks) = empty_chain(3);
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let genesis = blockchain.blocks[&genesis_hash].0.clone();
+        let invalid = generate_mined_block(&genesis, vec![coinbase(&genesis_hash, generate_rand_hash160(), blockchain.params.subsidy(1) + 1)]);
+        let child = generate_block(&invalid.hash(), vec![]);
+        blockchain.insert_header(&invalid.header).unwrap();
+        blockchain.insert_header(&child.header).unwrap();
+        for header in source.headers_after(&[genesis_hash], 1) {
+            blockchain.insert_header(&header).unwrap();
+        }
+        assert_eq!(blockchain.best_header(), child.hash());
+
+        // a block too far in the future may become valid later
+        blockchain.reject(&invalid.hash(), &BlockError::TimestampTooNew);
+        assert_eq!(blockchain.best_header(), child.hash());
+
+        // the worker validates the block before inserting it
+        let error = validate_block(&blockchain, &invalid).unwrap_err();
+        assert_eq!(error, BlockError::CoinbaseTooLarge);
+        blockchain.reject(&invalid.hash(), &error);
+        assert!(!blockchain.headers.contains_key(&invalid.hash()));
+        assert!(!blockchain.headers.contains_key(&child.hash()));
+        assert_eq!(blockchain.best_header(), blocks[0].hash());
+        assert_eq!(blockchain.missing_blocks(10), vec![blocks[0].hash()]);
+    }
 }
@@ -65,6 +65,17 @@ impl std::fmt::Display for H256 {
     }
 }
 
+/// Parse the 64 hex digits `Display` prints
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<H256, Self::Err> {
+        let mut buffer: [u8; 32] = [0; 32];
+        hex::decode_to_slice(s, &mut buffer)?;
+        Ok(H256(buffer))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -163,10 +174,306 @@ impl PartialOrd for H256 {
     }
 }
 
+/// A 256 bit unsigned integer, for arithmetic on targets and chain work.
+/// Operators panic on overflow like the primitive types in debug builds.
+#[derive(Eq, PartialEq, Clone, Copy, Hash, Default)]
+pub struct U256([u64; 4]); // little endian 64 bit limbs
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (limb, (a, b)) in limbs.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            let (sum, c1) = a.overflowing_add(*b);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        (U256(limbs), carry)
+    }
+
+    pub fn overflowing_sub(self, other: U256) -> (U256, bool) {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for (limb, (a, b)) in limbs.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            let (diff, b1) = a.overflowing_sub(*b);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+        (U256(limbs), borrow)
+    }
+
+    pub fn overflowing_mul(self, other: U256) -> (U256, bool) {
+        // schoolbook multiplication into 512 bits
+        let mut limbs = [0u64; 8];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry: u128 = 0;
+            for (j, b) in other.0.iter().enumerate() {
+                let cur = limbs[i + j] as u128 + *a as u128 * *b as u128 + carry;
+                limbs[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            limbs[i + 4] = carry as u64;
+        }
+        let overflow = limbs[4..].iter().any(|limb| *limb != 0);
+        (U256([limbs[0], limbs[1], limbs[2], limbs[3]]), overflow)
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        match self.overflowing_add(other) {
+            (sum, false) => Some(sum),
+            _ => None,
+        }
+    }
+
+    pub fn checked_sub(self, other: U256) -> Option<U256> {
+        match self.overflowing_sub(other) {
+            (diff, false) => Some(diff),
+            _ => None,
+        }
+    }
+
+    pub fn checked_mul(self, other: U256) -> Option<U256> {
+        match self.overflowing_mul(other) {
+            (product, false) => Some(product),
+            _ => None,
+        }
+    }
+
+    pub fn saturating_add(self, other: U256) -> U256 {
+        self.checked_add(other).unwrap_or(U256::MAX)
+    }
+
+    pub fn saturating_mul(self, other: U256) -> U256 {
+        self.checked_mul(other).unwrap_or(U256::MAX)
+    }
+
+    /// Quotient and remainder, panics if `divisor` is zero
+    pub fn div_rem(self, divisor: U256) -> (U256, U256) {
+        assert!(!divisor.is_zero(), "attempt to divide by zero");
+        // binary long division
+        let mut quotient = U256::ZERO;
+        let mut rem = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            // the remainder may take 257 bits for a moment
+            let high = rem.bit(255);
+            rem = rem << 1;
+            rem.0[0] |= self.bit(i) as u64;
+            if high || rem >= divisor {
+                rem = rem.overflowing_sub(divisor).0;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+        }
+        (quotient, rem)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    /// Number of bits needed to represent the value
+    pub fn bits(&self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate().rev() {
+            if *limb != 0 {
+                return 64 * i as u32 + 64 - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    fn bit(&self, i: u32) -> bool {
+        (self.0[(i / 64) as usize] >> (i % 64)) & 1 == 1
+    }
+
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    /// Decode a compact target, like the "bits" field of a Bitcoin header:
+    /// the high byte is a size in bytes and the 23 low bits a mantissa, bit 23 is a sign.
+    /// None for negative or overflowing values.
+    pub fn from_compact(compact: u32) -> Option<U256> {
+        let size = compact >> 24;
+        let word = compact & 0x007f_ffff;
+        if word == 0 {
+            return Some(U256::ZERO);
+        }
+        if compact & 0x0080_0000 != 0 {
+            return None;
+        }
+        if size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32) {
+            return None;
+        }
+        if size <= 3 {
+            Some(U256::from((word >> (8 * (3 - size))) as u64))
+        } else {
+            Some(U256::from(word as u64) << (8 * (size - 3)))
+        }
+    }
+
+    /// Encode to a compact target, keeping the 3 most significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).low_u64() as u32
+        };
+        // the mantissa is positive, move its sign bit to the next byte
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | size << 24
+    }
+}
+
+impl std::convert::From<u64> for U256 {
+    fn from(input: u64) -> U256 {
+        U256([input, 0, 0, 0])
+    }
+}
+
+impl std::convert::From<u128> for U256 {
+    fn from(input: u128) -> U256 {
+        U256([input as u64, (input >> 64) as u64, 0, 0])
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        let mut limbs = [0u64; 4];
+        for (limb, bytes) in limbs.iter_mut().zip(input.0.chunks(8).rev()) {
+            *limb = u64::from_be_bytes(bytes.try_into().unwrap());
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut raw = [0u8; 32];
+        for (bytes, limb) in raw.chunks_mut(8).rev().zip(input.0.iter()) {
+            bytes.copy_from_slice(&limb.to_be_bytes());
+        }
+        H256(raw)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::ops::Add for U256 {
+    type Output = U256;
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(other).expect("attempt to add with overflow")
+    }
+}
+
+impl std::ops::Sub for U256 {
+    type Output = U256;
+    fn sub(self, other: U256) -> U256 {
+        self.checked_sub(other).expect("attempt to subtract with overflow")
+    }
+}
+
+impl std::ops::Mul for U256 {
+    type Output = U256;
+    fn mul(self, other: U256) -> U256 {
+        self.checked_mul(other).expect("attempt to multiply with overflow")
+    }
+}
+
+impl std::ops::Div for U256 {
+    type Output = U256;
+    fn div(self, other: U256) -> U256 {
+        self.div_rem(other).0
+    }
+}
+
+impl std::ops::Rem for U256 {
+    type Output = U256;
+    fn rem(self, other: U256) -> U256 {
+        self.div_rem(other).1
+    }
+}
+
+impl std::ops::Not for U256 {
+    type Output = U256;
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+/// Bits shifted out are lost, shifting by 256 or more gives zero
+impl std::ops::Shl<u32> for U256 {
+    type Output = U256;
+    fn shl(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        if shift >= 256 {
+            return U256(limbs);
+        }
+        let (skip, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs.iter_mut().enumerate().skip(skip) {
+            *limb = self.0[i - skip] << bits;
+            if bits > 0 && i > skip {
+                *limb |= self.0[i - skip - 1] >> (64 - bits);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl std::ops::Shr<u32> for U256 {
+    type Output = U256;
+    fn shr(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        if shift >= 256 {
+            return U256(limbs);
+        }
+        let (skip, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs.iter_mut().enumerate().take(4 - skip) {
+            *limb = self.0[i + skip] >> bits;
+            if bits > 0 && i + skip < 3 {
+                *limb |= self.0[i + skip + 1] << (64 - bits);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", H256::from(*self))
+    }
+}
+
+impl std::fmt::Debug for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub mod tests {
-    use super::H256;
+    use super::{H256, U256};
     use rand::Rng;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
 
     pub fn generate_random_hash() -> H256 {
         let mut rng = rand::thread_rng();
@@ -176,4 +483,101 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    fn big(x: U256) -> BigUint {
+        BigUint::from_bytes_be(H256::from(x).as_ref())
+    }
+
+    /// Reference value, None if it doesn't fit in 256 bits
+    fn small(x: BigUint) -> Option<U256> {
+        let bytes = x.to_bytes_be();
+        if bytes.len() > 32 {
+            return None;
+        }
+        let mut raw = [0u8; 32];
+        raw[32 - bytes.len()..].copy_from_slice(&bytes);
+        Some(U256::from(H256::from(raw)))
+    }
+
+    /// Values of every size, not only the large ones uniform limbs give
+    fn u256() -> impl Strategy<Value = U256> {
+        (any::<[u8; 32]>(), 0u32..=256).prop_map(|(raw, bits)| U256::from(H256::from(raw)) >> (256 - bits))
+    }
+
+    proptest! {
+        #[test]
+        fn u256_arithmetic(a in u256(), b in u256()) {
+            prop_assert_eq!(a.checked_add(b), small(big(a) + big(b)));
+            prop_assert_eq!(a.checked_mul(b), small(big(a) * big(b)));
+            if a >= b {
+                prop_assert_eq!(a.checked_sub(b), small(big(a) - big(b)));
+            } else {
+                prop_assert_eq!(a.checked_sub(b), None);
+            }
+            if !b.is_zero() {
+                prop_assert_eq!(Some(a / b), small(big(a) / big(b)));
+                prop_assert_eq!(Some(a % b), small(big(a) % big(b)));
+            }
+            prop_assert_eq!(a.cmp(&b), big(a).cmp(&big(b)));
+            prop_assert_eq!(a.bits() as u64, big(a).bits());
+        }
+
+        #[test]
+        fn u256_shifts(a in u256(), shift in 0u32..300) {
+            let mask = (BigUint::from(1u8) << 256usize) - 1u8;
+            prop_assert_eq!(Some(a << shift), small((big(a) << shift as usize) & mask));
+            prop_assert_eq!(Some(a >> shift), small(big(a) >> shift as usize));
+        }
+
+        #[test]
+        fn u256_h256_roundtrip(raw in any::<[u8; 32]>()) {
+            let hash = H256::from(raw);
+            prop_assert_eq!(H256::from(U256::from(hash)), hash);
+            prop_assert_eq!(U256::from(hash).cmp(&U256::from(H256::from([0x80; 32]))), hash.cmp(&H256::from([0x80; 32])));
+        }
+
+        #[test]
+        fn compact_encode(a in u256()) {
+            // keep the 3 most significant bytes, with a clear sign bit
+            let mut size = big(a).bits().div_ceil(8);
+            let mut mantissa = if size <= 3 { big(a) << (8 * (3 - size) as usize) } else { big(a) >> (8 * (size - 3) as usize) };
+            if mantissa.bit(23) {
+                mantissa >>= 8;
+                size += 1;
+            }
+            let expected = mantissa.to_u32_digits().first().copied().unwrap_or(0) | (size as u32) << 24;
+            prop_assert_eq!(a.to_compact(), expected);
+
+            let decoded = U256::from_compact(a.to_compact()).unwrap();
+            prop_assert!(decoded <= a);
+            prop_assert_eq!(decoded.to_compact(), a.to_compact());
+        }
+
+        #[test]
+        fn compact_decode(compact in any::<u32>()) {
+            let size = compact >> 24;
+            let word = compact & 0x007f_ffff;
+            let value = if size <= 3 {
+                BigUint::from(word >> (8 * (3 - size)))
+            } else {
+                BigUint::from(word) << (8 * (size - 3) as usize)
+            };
+            let expected = if word != 0 && compact & 0x0080_0000 != 0 { None } else { small(value) };
+            prop_assert_eq!(U256::from_compact(compact), expected);
+        }
+    }
+
+    #[test]
+    fn compact_known_values() {
+        let mut raw = [0u8; 32];
+        raw[4..6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(U256::from_compact(0x1d00ffff), Some(U256::from(H256::from(raw))));
+        assert_eq!(U256::from(H256::from(raw)).to_compact(), 0x1d00ffff);
+        assert_eq!(U256::from_compact(0x05009234), Some(U256::from(0x92340000u64)));
+        assert_eq!(U256::from_compact(0x01003456), Some(U256::ZERO));
+        assert_eq!(U256::from_compact(0x01123456), Some(U256::from(0x12u64)));
+        assert_eq!(U256::from_compact(0x04923456), None);
+        assert_eq!(U256::from_compact(0xff123456), None);
+        assert_eq!(U256::from(0x80u64).to_compact(), 0x02008000);
+        assert_eq!(U256::ZERO.to_compact(), 0);
+    }
 }
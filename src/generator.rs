@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
-use crate::signedtrans::{generate_random_signedtrans, SignedTrans};
+use crate::signedtrans::SignedTrans;
+use crate::network::inventory::Inv;
 use crate::network::message::Message;
 use crate::mempool::Mempool;
 
@@ -15,10 +16,10 @@ use std::time;
 
 use std::thread;
 use rand::Rng;
-use ring::signature::KeyPair;
-use crate::crypto::hash::{generate_rand_hash256, H160, H256, Hashable};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::crypto::hash::{generate_rand_hash256, H160, Hashable};
 use crate::crypto::key_pair;
-use crate::transaction::{Input, Output, sign, Transaction};
+use crate::transaction::{Input, OutPoint, Output, Transaction};
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
@@ -39,6 +40,7 @@ pub struct Context {
     bc: Arc<Mutex<Blockchain>>,
     mp: Arc<Mutex<Mempool>>,
     start_time: SystemTime,
+    miner_key: Arc<Ed25519KeyPair>,
 }
 
 #[derive(Clone)]
@@ -50,7 +52,8 @@ pub struct Generator {
 pub fn new(
     server: &ServerHandle,
     bc: &Arc<Mutex<Blockchain>>,
-    mp: &Arc<Mutex<Mempool>>
+    mp: &Arc<Mutex<Mempool>>,
+    miner_key: &Arc<Ed25519KeyPair>,
 ) -> (Context, Generator) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -61,6 +64,7 @@ pub fn new(
         bc: Arc::clone(bc),
         mp: Arc::clone(mp),
         start_time: SystemTime::now(),
+        miner_key: Arc::clone(miner_key),
     };
 
     let generator = Generator {
@@ -138,8 +142,12 @@ impl Context {
             }
 
             if flag {
-                for i in 0..3 {
-                    let key = key_pair::random();
+                // the miner's coinbases are the only source of money, so its key is spent from too
+                let mut keys = vec![Arc::clone(&self.miner_key)];
+                for _ in 0..3 {
+                    keys.push(Arc::new(key_pair::random()));
+                }
+                for key in keys {
                     let public_key = key.public_key();
                     let byte_pbkey = public_key.as_ref();
                     let address = H160::hash(&byte_pbkey);
@@ -150,88 +158,61 @@ impl Context {
                     key_map.insert(address, key);
                 }
                 println!("all addresses: {:?}", self.bc.lock().unwrap().address_list);
-
-                // init money
-                let mut bc = self.bc.lock().unwrap();
-                for addr in bc.address_list.clone(){
-                    let mut init = vec![];
-                    init.push(Output{
-                        balance:0,
-                        address:addr,
-                    });
-                    let trans = Transaction{id: generate_rand_hash256(), inputs: vec![], outputs: init};
-                    let key = key_pair::random();
-                    let trans = SignedTrans{
-                        transaction: trans.clone(),
-                        signature: sign(&trans, &key),
-                        public_key: key.public_key().as_ref().to_vec(),
-                    };
-                    bc.update_state(&trans.clone(), self.mp.lock().unwrap().clone().pool.len());
-                    let msg = Message::NewTransactionHashes(vec![trans.hash()]);
-                    self.server.broadcast(msg);
-                }
-                drop(bc);
             }
             flag = false;
 
             // get blockchain state
-            let mut bc = self.bc.lock().unwrap();
-            let state = bc.clone().current_state;
+            let bc = self.bc.lock().unwrap();
+            let state = &bc.current_state;
+
+            // outputs already spent by a pending transaction can't be spent again
+            let pending: HashSet<OutPoint> = self.mp.lock().unwrap().pool.values()
+                .flat_map(|tx| tx.transaction.input_outpoints())
+                .collect();
+            let spendable: Vec<(&OutPoint, &Output)> = state.map.iter()
+                .filter(|(_, out)| key_map.contains_key(&out.address))
+                .filter(|(outpoint, _)| !pending.contains(outpoint))
+                .collect();
 
             // generate in & out
             let mut rng = rand::thread_rng();
             let chance:u8 = rng.gen();
-            let mut from_key = &key_pair::random();
-            let mut from_tx = generate_rand_hash256();
-            if chance % 10 < 7 {
-                let mut skip:u8 = rng.gen();
-                skip %= state.sig.len() as u8;
-                for (_, tx) in state.sig {
-                    let from_add = &tx.transaction.outputs[0].address;
-                    if key_map.contains_key(from_add) {
-                        from_key = key_map.get(from_add).unwrap();
-                        from_tx = tx.transaction.id;
-                    }
-                    if skip == 0 {
-                        break;
-                    }
-                    skip -= 1;
-                }
+            let random_key = Arc::new(key_pair::random());
+            let mut from_key = &random_key;
+            let mut from = OutPoint{ txid: generate_rand_hash256(), index: 0 };
+            let mut value = 1;
+            if chance % 10 < 7 && !spendable.is_empty() {
+                let (outpoint, out) = spendable[rng.gen_range(0, spendable.len())];
+                from_key = key_map.get(&out.address).unwrap();
+                from = *outpoint;
+                value = out.balance;
             }
-            let inputs = Input{index: 1, previous_hash:from_tx};
+            let inputs = Input{index: from.index, previous_hash: from.txid};
 
+            // pay part of the input to a random address, and the change back to the sender
             let mut val:u8 = rng.gen();
             val %= bc.address_list.len() as u8;
             let dest_address = bc.address_list[val as usize];
-            let outputs = Output{ balance: 1, address:dest_address};
-
-            let id = generate_rand_hash256();
-            let trans = Transaction{id, inputs:vec![inputs], outputs:vec![outputs] };
+            let amount = rng.gen_range(0, value.saturating_add(1));
+            let mut outputs = vec![Output{ balance: amount, address:dest_address}];
+            if amount < value {
+                let change_address = H160::hash(from_key.public_key().as_ref());
+                outputs.push(Output{ balance: value - amount, address: change_address});
+            }
 
-            // generate signature
-            let s = sign(&trans, &from_key);
-            let p = from_key.public_key().as_ref().to_vec();
+            let trans = Transaction{inputs:vec![inputs], outputs };
 
             // generate trans using state (may be invalid)
-            let trans = SignedTrans{
-                transaction: trans,
-                signature: s,
-                public_key: p,
-            };
-
-            // get mempool
-            let mut mp = self.mp.lock().unwrap();
+            let trans = SignedTrans::new(trans, from_key);
 
-            // add to mempool
-            mp.add(&trans);
-            drop(mp);
-
-            bc.update_state(&trans.clone(), self.mp.lock().unwrap().clone().pool.len());
+            // only valid transactions enter the mempool, the state changes once they are mined
+            let accepted = self.mp.lock().unwrap().accept(&trans, state).is_ok();
             drop(bc);
 
-            // broadcast
-            let msg = Message::NewTransactionHashes(vec![trans.hash()]);
-            self.server.broadcast(msg);
+            if accepted {
+                // announce to peers
+                self.server.announce(vec![Inv::Transaction(trans.hash())]);
+            }
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
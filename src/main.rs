@@ -13,6 +13,11 @@ mod signedtrans;
 mod mempool;
 mod state;
 mod generator;
+mod validation;
+mod params;
+mod store;
+mod snapshot;
+mod clock;
 
 use clap::clap_app;
 use crossbeam::channel;
@@ -20,12 +25,23 @@ use log::{error, info};
 use api::Server as ApiServer;
 use network::{server, worker};
 use std::net;
+use std::path::Path;
 use std::process;
 use std::thread;
 use std::time;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
 use crate::mempool::Mempool;
+use crate::crypto::key_pair;
+use crate::params::ChainParams;
+use crate::network::address_book::AddressBook;
+use crate::clock::unix_now;
+use crate::network::inventory::Inventory;
+use crate::network::sync::Downloads;
+use crate::network::peer::MAX_FRAME_SIZE;
+use crate::snapshot::Snapshot;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::Amount;
 
 fn main() {
     // parse command line arguments
@@ -37,6 +53,17 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg rbf: --rbf "Lets a transaction paying a higher fee replace the pending ones it conflicts with")
+     (@arg mempool_size: --("mempool-size") [BYTES] "Sets the bytes of pending transactions kept, the lowest fee rates are evicted past it")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] "Sets the largest message accepted from a peer, larger ones disconnect it")
+     (@arg datadir: --datadir [DIR] "Sets the directory the blocks are stored in, the chain stays in memory without it")
+     (@arg subsidy: --subsidy [INT] "Sets the value created by the coinbase before the first halving")
+     (@arg halving_interval: --("halving-interval") [INT] "Sets the number of blocks between two halvings of the subsidy")
+     (@arg max_block_size: --("max-block-size") [BYTES] "Sets the largest serialized size of a block")
+     (@arg retarget_interval: --("retarget-interval") [INT] "Sets the number of blocks between two difficulty adjustments")
+     (@arg block_time: --("block-time") [MS] "Sets the block time the difficulty adjustments aim at, in milliseconds")
+     (@arg snapshot: --snapshot [FILE] "Starts from a UTXO snapshot instead of the genesis block")
+     (@arg assume_utxo: --("assume-utxo") +takes_value value_name("BLOCK:HASH") "Sets the snapshot --snapshot must match: BLOCK is the hash of the block the snapshot was taken at, HASH the hash of the snapshot, both in hex")
     )
     .get_matches();
 
@@ -64,11 +91,95 @@ fn main() {
             process::exit(1);
         });
 
+    // consensus parameters, every node of the network must use the same ones
+    let mut params = ChainParams::default();
+    if let Some(subsidy) = matches.value_of("subsidy") {
+        params.initial_subsidy = subsidy.parse::<Amount>().unwrap_or_else(|e| {
+            error!("Error parsing subsidy: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(interval) = matches.value_of("halving_interval") {
+        params.halving_interval = interval.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing halving interval: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(size) = matches.value_of("max_block_size") {
+        params.max_block_size = size.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing max block size: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(interval) = matches.value_of("retarget_interval") {
+        params.retarget_interval = interval.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing retarget interval: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(time) = matches.value_of("block_time") {
+        params.target_block_time = time.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing block time: {}", e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(assume) = matches.value_of("assume_utxo") {
+        let parsed = match assume.split_once(':') {
+            Some((block, hash)) => block.parse::<H256>().and_then(|block| Ok((block, hash.parse::<H256>()?))),
+            None => Err(hex::FromHexError::InvalidStringLength),
+        };
+        params.assume_utxo = Some(parsed.unwrap_or_else(|e| {
+            error!("Error parsing assumed snapshot {}: {}", assume, e);
+            process::exit(1);
+        }));
+    }
+
+    // start from a snapshot if one is given, its hash must be the one the parameters expect
+    let blockchain = match matches.value_of("snapshot") {
+        Some(path) => {
+            let snapshot = Snapshot::load(Path::new(path)).unwrap_or_else(|e| {
+                error!("Error reading snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            let blockchain = Blockchain::from_snapshot(params, &snapshot).unwrap_or_else(|e| {
+                error!("Error loading snapshot {} with hash {}: {:?}", path, snapshot.hash(), e);
+                process::exit(1);
+            });
+            info!("Loaded snapshot of block {} at height {}", snapshot.base(), snapshot.height);
+            blockchain
+        }
+        None => Blockchain::with_params(params),
+    };
+
+    // reload the blocks stored by a previous run
+    let blockchain = match matches.value_of("datadir") {
+        Some(dir) => blockchain.load_store(Path::new(dir)).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => blockchain,
+    };
+    info!("Loaded {} blocks, chain height {}", blockchain.get_block_num(), blockchain.get_length());
+
+    let bc = Arc::new(Mutex::new(blockchain));
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let max_frame_size = match matches.value_of("max_frame_size") {
+        Some(size) => size.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing max frame size: {}", e);
+            process::exit(1);
+        }),
+        None => MAX_FRAME_SIZE,
+    };
+
+    // start the p2p server, it connects to the peers of the address book
+    let addr_book = Arc::new(Mutex::new(AddressBook::new()));
+    let inventory = Arc::new(Mutex::new(Inventory::new()));
+    let downloads = Arc::new(Mutex::new(Downloads::new()));
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, &bc, &addr_book, &inventory, &downloads, max_frame_size).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
@@ -81,22 +192,34 @@ fn main() {
             process::exit(1);
         });
 
-    let bc = Arc::new(Mutex::new(Blockchain::new()));
-    let mem_pool = Arc::new(Mutex::new(Mempool::new()));
+    let mut mempool = Mempool::new();
+    if let Some(size) = matches.value_of("mempool_size") {
+        mempool.max_bytes = size.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing mempool size: {}", e);
+            process::exit(1);
+        });
+    }
+    mempool.replace_by_fee = matches.is_present("rbf");
+    let mem_pool = Arc::new(Mutex::new(mempool));
     let worker_ctx = worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &bc,
-        &mem_pool
+        &mem_pool,
+        &addr_book,
+        &inventory,
+        &downloads,
     );
     worker_ctx.start();
 
-    // start the miner
+    // start the miner, the generator spends what it mines
+    let miner_key = Arc::new(key_pair::random());
     let (miner_ctx, miner) = miner::new(
         &server,
         &bc,
-        &mem_pool
+        &mem_pool,
+        &miner_key,
     );
     miner_ctx.start();
 
@@ -104,7 +227,8 @@ fn main() {
     let (generator_ctx, generator) = generator::new(
         &server,
         &bc,
-        &mem_pool
+        &mem_pool,
+        &miner_key,
     );
     generator_ctx.start();
 
@@ -112,6 +236,7 @@ fn main() {
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let addr_book = Arc::clone(&addr_book);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -122,6 +247,8 @@ fn main() {
                             break;
                         }
                     };
+                    // the connection manager reconnects after a drop
+                    addr_book.lock().unwrap().add_fixed(addr, unix_now());
                     match server.connect(addr) {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
@@ -148,6 +275,8 @@ fn main() {
         &miner,
         &generator,
         &server,
+        &bc,
+        &mem_pool,
     );
 
     loop {
@@ -1,41 +1,862 @@
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use std::ptr::addr_of_mut;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use crate::block::Block;
 use crate::crypto::hash::{H256, Hashable};
+use crate::clock::unix_now;
 use crate::signedtrans::SignedTrans;
+use crate::state::{State, TxError};
+use crate::transaction::{Amount, OutPoint};
 
+/// Compare the fee rates (fee per serialized byte) of two transactions, without rounding
+fn cmp_fee_rate((fee_a, size_a): (Amount, usize), (fee_b, size_b): (Amount, usize)) -> Ordering {
+    (fee_a as u128 * size_b as u128).cmp(&(fee_b as u128 * size_a as u128))
+}
+
+/// A pending transaction ordered by fee rate, for the block template to pick the highest first
+/// and the trimming to evict the lowest first
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    txid: H256,
+    fee: Amount,
+    size: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // the lowest hash first among equal fee rates, for the template not to depend on the map order
+        cmp_fee_rate((self.fee, self.size), (other.fee, other.size)).then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Bytes of pending transactions kept by default
+pub const MAX_MEMPOOL_BYTES: usize = 32 * 1024 * 1024;
+/// A transaction pending for longer than this many seconds is dropped
+pub const MEMPOOL_EXPIRY: u64 = 72 * 60 * 60;
+/// Added to the fee rate of an evicted transaction to get the new minimum, per 1000 bytes
+const INCREMENTAL_FEE: Amount = 1000;
+/// Most transactions a replacement may evict, with their descendants
+const MAX_REPLACEMENTS: usize = 100;
+/// Longest chain of pending transactions, counting the last one
+pub const MAX_ANCESTORS: usize = 25;
+/// Most pending transactions depending on one, counting itself
+pub const MAX_DESCENDANTS: usize = 25;
+/// The minimum fee rate raised by evictions halves every this many seconds
+const MIN_FEE_HALF_LIFE: u64 = 12 * 60 * 60;
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+/// Why a transaction is kept out of the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// the transaction can't be executed on the ledger
+    Invalid(TxError),
+    /// its fee rate is below the minimum raised by evictions
+    FeeTooLow,
+    /// the mempool is full of transactions paying a better fee rate
+    Full,
+    /// an output it spends is already spent by a pending transaction
+    Conflict,
+    /// the replacement doesn't pay enough to evict the transactions it conflicts with
+    ReplacementFee,
+    /// the replacement would evict more than `MAX_REPLACEMENTS` transactions
+    TooManyReplacements,
+    /// the replacement spends pending outputs the replaced transactions didn't
+    ReplacementInputs,
+    /// it would have more than `MAX_ANCESTORS` pending ancestors, or give one of them more than
+    /// `MAX_DESCENDANTS` pending descendants
+    TooLongChain,
+    /// these transactions, whose outputs it spends, are neither confirmed nor pending
+    MissingInputs(Vec<H256>),
+}
+
+/// What is known of a pending transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Entry {
+    size: usize,
+    fee: Amount,
+    time: u64, // when it entered the mempool, in unix seconds
+}
+
+/// Usage of the mempool, reported through the API
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolStats {
+    pub transactions: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub min_fee_per_kb: Amount,
+    pub evicted: u64,
+    pub expired: u64,
+    pub replaced: u64,
+}
+
+/// Pending transactions, up to `max_bytes`. When full, the lowest fee rate goes first and
+/// raises the fee rate a new transaction must pay.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Mempool {
     pub pool: HashMap<H256, SignedTrans>,
+    entries: HashMap<H256, Entry>,
+    spends: HashMap<OutPoint, H256>, // each output spent by a pending transaction, and which one
+    bytes: usize,
+    pub max_bytes: usize,
+    min_fee_per_kb: Amount, // decays back to nothing after the evictions stop
+    min_fee_time: u64, // when the minimum fee rate was last raised or halved
+    evicted: u64, // transactions evicted for room since the start
+    expired: u64, // transactions expired since the start
+    replaced: u64, // transactions replaced by fee since the start
+    pub replace_by_fee: bool, // a conflicting transaction may replace the pending ones when it pays more
+    changes: u64, // bumped whenever a transaction enters or leaves
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool {
+            pool: HashMap::new(),
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            bytes: 0,
+            max_bytes: MAX_MEMPOOL_BYTES,
+            min_fee_per_kb: 0,
+            min_fee_time: 0,
+            evicted: 0,
+            expired: 0,
+            replaced: 0,
+            replace_by_fee: false,
+            changes: 0,
+        }
+    }
 }
 
 impl Mempool {
     pub fn new() -> Self{
-        let m =Mempool {
-            pool: HashMap::new()
-        };
-        m
+        Self::default()
     }
 
-    pub fn add(&mut self, signed: &SignedTrans) {
-        let map = self.clone().pool;
+    /// Returns false if the transaction is already there or spends an output another one spends
+    fn insert(&mut self, signed: &SignedTrans, fee: Amount, time: u64) -> bool {
         let hash = signed.hash();
-        if !map.contains_key(&hash){
-            self.pool.insert(hash, signed.clone());
+        let outpoints = signed.transaction.input_outpoints();
+        if self.pool.contains_key(&hash) || outpoints.iter().any(|outpoint| self.spends.contains_key(outpoint)) {
+            return false;
+        }
+        for outpoint in outpoints {
+            self.spends.insert(outpoint, hash);
+        }
+        let size = signed.size();
+        self.bytes += size;
+        self.entries.insert(hash, Entry{ size, fee, time });
+        self.pool.insert(hash, signed.clone());
+        self.changes += 1;
+        true
+    }
+
+    fn remove_entry(&mut self, hash: &H256) -> bool {
+        let signed = match self.pool.remove(hash) {
+            Some(signed) => signed,
+            None => return false,
         };
+        for input in signed.transaction.inputs.iter() {
+            self.spends.remove(&input.outpoint());
+        }
+        self.bytes -= self.entries.remove(hash).unwrap().size;
+        self.changes += 1;
+        true
     }
 
-    pub fn remove(&mut self, signed: &SignedTrans) {
-        let map = self.clone().pool;
+    /// The pending transactions spending an output of `hash`
+    fn children(&self, hash: &H256) -> Vec<H256> {
+        let outputs = self.pool.get(hash).map_or(0, |tx| tx.transaction.outputs.len());
+        (0..outputs as u32)
+            .filter_map(|index| self.spends.get(&OutPoint{ txid: *hash, index }).cloned())
+            .collect()
+    }
+
+    /// A pending transaction and all the pending ones depending on it
+    pub fn descendants(&self, hash: &H256) -> HashSet<H256> {
+        let mut found = HashSet::new();
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            if self.pool.contains_key(&hash) && found.insert(hash) {
+                stack.extend(self.children(&hash));
+            }
+        }
+        found
+    }
+
+    /// Remove a transaction and the pending ones spending its outputs, returns how many went
+    fn remove_with_descendants(&mut self, hash: &H256) -> u64 {
+        let mut removed = 0;
+        for hash in self.descendants(hash) {
+            if self.remove_entry(&hash) {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Add a transaction without checking it, its fee is taken as zero.
+    /// Returns false if it is already there or conflicts with a pending one.
+    pub fn add(&mut self, signed: &SignedTrans) -> bool {
+        let added = self.insert(signed, 0, unix_now());
+        self.trim();
+        added && self.pool.contains_key(&signed.hash())
+    }
+
+    /// The pending transactions spending an output `signed` spends
+    pub fn conflicts(&self, signed: &SignedTrans) -> HashSet<H256> {
+        signed.transaction.inputs.iter()
+            .filter_map(|input| self.spends.get(&input.outpoint()).cloned())
+            .collect()
+    }
+
+    /// BIP125-like rules for `signed`, paying `fee`, to replace the `conflicts` and their descendants:
+    /// no pending parent the conflicts didn't have, a better fee rate than each conflict, a fee covering
+    /// all the replaced ones plus the relay of the replacement, and at most `MAX_REPLACEMENTS` replaced
+    fn check_replacement(&self, signed: &SignedTrans, fee: Amount, conflicts: &HashSet<H256>) -> Result<(), MempoolError> {
+        let conflict_parents: HashSet<H256> = conflicts.iter()
+            .flat_map(|hash| self.parents(&self.pool[hash]))
+            .collect();
+        if !self.parents(signed).is_subset(&conflict_parents) {
+            return Err(MempoolError::ReplacementInputs);
+        }
+        let size = signed.size();
+        if conflicts.iter().any(|hash| {
+            let entry = &self.entries[hash];
+            cmp_fee_rate((fee, size), (entry.fee, entry.size)) != Ordering::Greater
+        }) {
+            return Err(MempoolError::ReplacementFee);
+        }
+        let mut replaced = HashSet::new();
+        for hash in conflicts {
+            replaced.extend(self.descendants(hash));
+        }
+        if replaced.len() > MAX_REPLACEMENTS {
+            return Err(MempoolError::TooManyReplacements);
+        }
+        let replaced_fees = replaced.iter().try_fold(0 as Amount, |total, hash| total.checked_add(self.entries[hash].fee));
+        let relay_fee = (INCREMENTAL_FEE as u128 * size as u128).div_ceil(1000) as Amount;
+        match replaced_fees.and_then(|total| total.checked_add(relay_fee)) {
+            Some(required) if fee >= required => Ok(()),
+            _ => Err(MempoolError::ReplacementFee),
+        }
+    }
+
+    /// The outputs `signed` spends, from the ledger or from pending transactions,
+    /// and the transactions whose outputs are in neither
+    fn spent_outputs(&self, signed: &SignedTrans, state: &State) -> (State, Vec<H256>) {
+        let mut view = State::new();
+        let mut missing = Vec::new();
+        for input in signed.transaction.inputs.iter() {
+            let outpoint = input.outpoint();
+            let output = match (state.map.get(&outpoint), self.pool.get(&input.previous_hash)) {
+                (Some(output), _) => output,
+                (None, Some(parent)) => match parent.transaction.outputs.get(input.index as usize) {
+                    Some(output) => output,
+                    None => continue,
+                },
+                (None, None) => {
+                    if !missing.contains(&input.previous_hash) {
+                        missing.push(input.previous_hash);
+                    }
+                    continue;
+                }
+            };
+            view.map.insert(outpoint, output.clone());
+        }
+        (view, missing)
+    }
+
+    /// The pending transactions `signed` spends an output of
+    pub fn parents(&self, signed: &SignedTrans) -> HashSet<H256> {
+        signed.transaction.inputs.iter()
+            .map(|input| input.previous_hash)
+            .filter(|txid| self.pool.contains_key(txid))
+            .collect()
+    }
+
+    /// The pending transactions `signed` depends on, directly or not
+    pub fn ancestors(&self, signed: &SignedTrans) -> HashSet<H256> {
+        let mut found = HashSet::new();
+        let mut stack: Vec<H256> = self.parents(signed).into_iter().collect();
+        while let Some(hash) = stack.pop() {
+            if found.insert(hash) {
+                stack.extend(self.parents(&self.pool[&hash]));
+            }
+        }
+        found
+    }
+
+    /// Admit a transaction after checking it against the ledger state and the outputs of the pending
+    /// transactions, which are left unchanged, and against the minimum fee rate and the chain limits.
+    /// A transaction spending outputs of unknown transactions is refused with `MissingInputs`.
+    pub fn accept(&mut self, signed: &SignedTrans, state: &State) -> Result<(), MempoolError> {
+        let (view, missing) = self.spent_outputs(signed, state);
+        let fee = match view.check(signed) {
+            Err(TxError::DoubleSpend) if !missing.is_empty() => return Err(MempoolError::MissingInputs(missing)),
+            result => result.map_err(MempoolError::Invalid)?,
+        };
         let hash = signed.hash();
-        if map.contains_key(&hash) {
-            self.pool.remove(&hash);
+        if self.pool.contains_key(&hash) {
+            return Ok(());
+        }
+        let now = unix_now();
+        self.expire(now);
+        if cmp_fee_rate((fee, signed.size()), (self.min_fee_per_kb, 1000)) == Ordering::Less {
+            return Err(MempoolError::FeeTooLow);
+        }
+        let ancestors = self.ancestors(signed);
+        if ancestors.len() + 1 > MAX_ANCESTORS
+            || ancestors.iter().any(|hash| self.descendants(hash).len() + 1 > MAX_DESCENDANTS) {
+            return Err(MempoolError::TooLongChain);
+        }
+        let conflicts = self.conflicts(signed);
+        if !conflicts.is_empty() {
+            if !self.replace_by_fee {
+                return Err(MempoolError::Conflict);
+            }
+            self.check_replacement(signed, fee, &conflicts)?;
+        }
+        // the replaced transactions are kept aside until the replacement is known to fit
+        let replaced: HashSet<H256> = conflicts.iter().flat_map(|hash| self.descendants(hash)).collect();
+        let mut replaced: Vec<(SignedTrans, Entry)> = replaced.iter()
+            .map(|hash| (self.pool[hash].clone(), self.entries[hash]))
+            .collect();
+        for (tx, _) in replaced.iter() {
+            self.remove_entry(&tx.hash());
+        }
+        self.insert(signed, fee, now);
+        self.trim();
+        if !self.pool.contains_key(&hash) {
+            // put them back, parents first, unless trimming evicted what they spend
+            loop {
+                let left = replaced.len();
+                replaced.retain(|(tx, entry)| {
+                    let spendable = tx.transaction.inputs.iter().all(|input|
+                        state.map.contains_key(&input.outpoint()) || self.pool.contains_key(&input.previous_hash));
+                    !(spendable && self.insert(tx, entry.fee, entry.time))
+                });
+                if replaced.len() == left {
+                    break;
+                }
+            }
+            return Err(MempoolError::Full);
+        }
+        self.replaced += replaced.len() as u64;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, signed: &SignedTrans) {
+        self.remove_entry(&signed.hash());
+    }
+
+    /// Evict the lowest fee rates until the pool fits in `max_bytes`, the minimum fee rate
+    /// then rises above the best evicted one
+    fn trim(&mut self) {
+        if self.bytes <= self.max_bytes {
+            return;
+        }
+        let mut lowest: BinaryHeap<Reverse<Candidate>> = self.entries.iter()
+            .map(|(txid, entry)| Reverse(Candidate{ txid: *txid, fee: entry.fee, size: entry.size }))
+            .collect();
+        while self.bytes > self.max_bytes {
+            let Reverse(entry) = lowest.pop().unwrap();
+            // already gone as the descendant of an evicted one
+            if !self.entries.contains_key(&entry.txid) {
+                continue;
+            }
+            self.evicted += self.remove_with_descendants(&entry.txid);
+            let fee_per_kb = (entry.fee as u128 * 1000).div_ceil(entry.size as u128) as Amount;
+            self.min_fee_per_kb = self.min_fee_per_kb.max(fee_per_kb.saturating_add(INCREMENTAL_FEE));
+            self.min_fee_time = unix_now();
         }
-        return
+    }
+
+    /// Drop the transactions pending for longer than `MEMPOOL_EXPIRY`, with their descendants,
+    /// and let the minimum fee rate decay
+    pub fn expire(&mut self, now: u64) {
+        let expired: Vec<H256> = self.entries.iter()
+            .filter(|(_, entry)| now.saturating_sub(entry.time) >= MEMPOOL_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.expired += self.remove_with_descendants(&hash);
+        }
+        while self.min_fee_per_kb > 0 && now.saturating_sub(self.min_fee_time) >= MIN_FEE_HALF_LIFE {
+            self.min_fee_per_kb /= 2;
+            self.min_fee_time += MIN_FEE_HALF_LIFE;
+            if self.min_fee_per_kb < INCREMENTAL_FEE / 2 {
+                self.min_fee_per_kb = 0;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            transactions: self.pool.len(),
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            min_fee_per_kb: self.min_fee_per_kb,
+            evicted: self.evicted,
+            expired: self.expired,
+            replaced: self.replaced,
+        }
+    }
+
+    /// Fee of a pending transaction, the inputs minus the outputs.
+    /// Its inputs may spend outputs of other pending transactions.
+    pub fn fee(&self, signed: &SignedTrans, state: &State) -> Option<Amount> {
+        let mut input_val: Amount = 0;
+        for input in signed.transaction.inputs.iter() {
+            let out = match state.map.get(&input.outpoint()) {
+                Some(out) => out,
+                None => self.pool.get(&input.previous_hash)?.transaction.outputs.get(input.index as usize)?,
+            };
+            input_val = input_val.checked_add(out.balance)?;
+        }
+        input_val.checked_sub(signed.transaction.output_val()?)
+    }
+
+    /// Changes whenever a transaction enters or leaves the pool, a block template built from it
+    /// stays current on the same tip as long as this doesn't change
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// Pick the transactions of a block mined on top of `state`, highest fee rate first,
+    /// up to `max_size` serialized bytes. A transaction spending the output of another pending
+    /// transaction only comes after it. Returns the transactions in block order and their fees.
+    pub fn block_template(&self, state: &State, max_size: usize) -> (Vec<SignedTrans>, Amount) {
+        // pending parents left to pick for each transaction, and the children each one unlocks
+        let mut waiting: HashMap<H256, usize> = HashMap::new();
+        let mut children: HashMap<H256, Vec<H256>> = HashMap::new();
+        let mut ready = BinaryHeap::new();
+        let mut candidates: HashMap<H256, Candidate> = HashMap::new();
+        for (txid, tx) in self.pool.iter() {
+            if let Some(fee) = self.fee(tx, state) {
+                candidates.insert(*txid, Candidate{ txid: *txid, fee, size: self.entries[txid].size });
+            }
+        }
+        for (txid, tx) in self.pool.iter() {
+            let mut parents: Vec<H256> = tx.transaction.inputs.iter()
+                .map(|input| input.previous_hash)
+                .filter(|parent| self.pool.contains_key(parent))
+                .collect();
+            parents.sort();
+            parents.dedup();
+            for parent in parents.iter() {
+                children.entry(*parent).or_default().push(*txid);
+            }
+            if parents.is_empty() {
+                ready.extend(candidates.remove(txid));
+            } else {
+                waiting.insert(*txid, parents.len());
+            }
+        }
+
+        let mut state = state.clone();
+        let mut trans = Vec::new();
+        let mut size = 0;
+        let mut fees: Amount = 0;
+        // the best fee rate among the transactions whose parents are all picked
+        while let Some(Candidate{ txid, size: tx_size, .. }) = ready.pop() {
+            let tx = &self.pool[&txid];
+            if size + tx_size > max_size {
+                continue;
+            }
+            match state.check(tx).ok().and_then(|fee| fees.checked_add(fee)) {
+                Some(total) => fees = total,
+                None => continue,
+            }
+            state.apply(tx);
+            size += tx_size;
+            trans.push(tx.clone());
+            for child in children.get(&txid).into_iter().flatten() {
+                let left = waiting.get_mut(child).unwrap();
+                *left -= 1;
+                if *left == 0 {
+                    ready.extend(candidates.remove(child));
+                }
+            }
+        }
+        (trans, fees)
+    }
+
+    /// Bring the pool in line with a new tip. The transactions of the `disconnected` blocks, oldest first,
+    /// come back, and whatever no longer applies on `state` is evicted, such as a double spend of an
+    /// input the new branch confirmed. Returns the hashes of the transactions no longer valid.
+    pub fn update(&mut self, disconnected: &[Block], state: &State) -> Vec<H256> {
+        let now = unix_now();
+        let mut candidates: Vec<(SignedTrans, u64)> = disconnected.iter()
+            .flat_map(|block| block.content.iter().filter(|tx| !tx.transaction.is_coinbase()).cloned())
+            .map(|tx| (tx, now))
+            .collect();
+        let entries = std::mem::take(&mut self.entries);
+        let mut pending: Vec<(SignedTrans, u64)> = self.pool.drain()
+            .map(|(hash, tx)| (tx, entries[&hash].time))
+            .collect();
+        pending.sort_by_key(|(tx, _)| tx.hash());
+        candidates.extend(pending);
+        self.spends.clear();
+        self.bytes = 0;
+        self.changes += 1;
+
+        // a transaction may spend another one of the pool, retry until nothing more fits
+        let mut state = state.clone();
+        loop {
+            let before = self.pool.len();
+            let mut left = Vec::new();
+            for (tx, time) in candidates {
+                if self.pool.contains_key(&tx.hash()) {
+                    continue;
+                }
+                match state.check(&tx) {
+                    Ok(fee) if self.insert(&tx, fee, time) => state.apply(&tx),
+                    _ => left.push((tx, time)),
+                }
+            }
+            candidates = left;
+            if self.pool.len() == before || candidates.is_empty() {
+                break;
+            }
+        }
+        self.trim();
+        let mut evicted: Vec<H256> = candidates.iter().map(|(tx, _)| tx.hash()).collect();
+        evicted.sort();
+        evicted.dedup();
+        evicted
     }
 
     pub fn print(&self) {
         println!("mempool: size:{:?}", self.pool.clone().len());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::{H160, generate_rand_hash256};
+    use crate::crypto::key_pair;
+    use crate::transaction::{Input, OutPoint, Output, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn spend(key: &Ed25519KeyPair, txid: H256) -> SignedTrans {
+        let t = Transaction{
+            inputs: vec![Input{index: 0, previous_hash: txid}],
+            outputs: vec![Output{balance: 5, address: H160::hash(key.public_key().as_ref())}],
+        };
+        SignedTrans::new(t, key)
+    }
+
+    /// Spend output `index` of `txid`, paying `balance` back to `key`
+    fn pay(key: &Ed25519KeyPair, txid: H256, index: u32, balance: Amount) -> SignedTrans {
+        let t = Transaction{
+            inputs: vec![Input{index, previous_hash: txid}],
+            outputs: vec![Output{balance, address: H160::hash(key.public_key().as_ref())}],
+        };
+        SignedTrans::new(t, key)
+    }
+
+    /// A state holding `count` outputs of 10 owned by `key`
+    fn funded_state(key: &Ed25519KeyPair, count: u32) -> (State, H256) {
+        let txid = generate_rand_hash256();
+        let mut state = State::new();
+        for index in 0..count {
+            state.map.insert(OutPoint{ txid, index }, Output{balance: 10, address: H160::hash(key.public_key().as_ref())});
+        }
+        (state, txid)
+    }
+
+    #[test]
+    fn template_by_fee_rate() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 3);
+        let low = pay(&key, txid, 0, 9);
+        let high = pay(&key, txid, 1, 5);
+        let mid = pay(&key, txid, 2, 7);
+        let mut mempool = Mempool::new();
+        for tx in [&low, &high, &mid] {
+            mempool.accept(tx, &state).unwrap();
+        }
+        assert_eq!(mempool.fee(&high, &state), Some(5));
+
+        let (trans, fees) = mempool.block_template(&state, usize::MAX);
+        let order: Vec<H256> = trans.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![high.hash(), mid.hash(), low.hash()]);
+        assert_eq!(fees, 9);
+
+        // only the best two fit
+        let (trans, fees) = mempool.block_template(&state, high.size() + mid.size());
+        assert_eq!(trans.len(), 2);
+        assert_eq!(fees, 8);
+    }
+
+    #[test]
+    fn template_parent_before_child() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 2);
+        // the parent has a second output, so it is larger than the child
+        let mut parent = pay(&key, txid, 0, 5);
+        parent.transaction.outputs.push(parent.transaction.outputs[0].clone());
+        let parent = SignedTrans::new(parent.transaction, &key);
+        let child = pay(&key, parent.hash(), 0, 1);
+        let other = pay(&key, txid, 1, 8);
+        let mut mempool = Mempool::new();
+        for tx in [&child, &parent, &other] {
+            mempool.add(tx);
+        }
+        assert_eq!(mempool.fee(&child, &state), Some(4));
+
+        let (trans, fees) = mempool.block_template(&state, usize::MAX);
+        let order: Vec<H256> = trans.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![other.hash(), parent.hash(), child.hash()]);
+        assert_eq!(fees, 6);
+
+        // the child is left out with its parent
+        let (trans, _) = mempool.block_template(&state, other.size() + child.size());
+        assert_eq!(trans.len(), 1);
+        assert_eq!(trans[0].hash(), other.hash());
+
+        // the miner rebuilds its template once the pool changed
+        let changes = mempool.changes();
+        mempool.remove(&other);
+        assert_ne!(mempool.changes(), changes);
+    }
+
+    #[test]
+    fn update_evicts_invalid() {
+        let key = key_pair::random();
+        let (mut state, txid) = funded_state(&key, 2);
+        let first = pay(&key, txid, 0, 9);
+        let child = pay(&key, first.hash(), 0, 7);
+        let other = pay(&key, txid, 1, 8);
+        let gone = pay(&key, generate_rand_hash256(), 0, 5);
+        let mut mempool = Mempool::new();
+        for tx in [&child, &first, &other, &gone] {
+            assert!(mempool.add(tx));
+        }
+
+        // a block spent the first output, the child goes with its parent
+        state.map.remove(&OutPoint{ txid, index: 0 });
+        let evicted = mempool.update(&[], &state);
+        let mut expected = vec![first.hash(), child.hash(), gone.hash()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(mempool.pool.len(), 1);
+        assert!(mempool.pool.contains_key(&other.hash()));
+        assert_eq!(mempool.stats().bytes, other.size());
+    }
+
+    #[test]
+    fn reject_conflict() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 1);
+        let first = pay(&key, txid, 0, 9);
+        let conflict = pay(&key, txid, 0, 2);
+        let mut mempool = Mempool::new();
+        mempool.accept(&first, &state).unwrap();
+        assert_eq!(mempool.accept(&conflict, &state), Err(MempoolError::Conflict));
+        assert!(!mempool.add(&conflict));
+        assert_eq!(mempool.conflicts(&conflict), vec![first.hash()].into_iter().collect());
+
+        // once the first is gone, its output can be spent again
+        mempool.remove(&first);
+        assert!(mempool.conflicts(&conflict).is_empty());
+        mempool.accept(&conflict, &state).unwrap();
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let key = key_pair::random();
+        let (mut state, txid) = funded_state(&key, 1);
+        state.map.get_mut(&OutPoint{ txid, index: 0 }).unwrap().balance = 100_000;
+        let original = pay(&key, txid, 0, 99_000);
+        let child = pay(&key, original.hash(), 0, 98_000);
+        let mut mempool = Mempool::new();
+        mempool.replace_by_fee = true;
+        mempool.accept(&original, &state).unwrap();
+        assert!(mempool.add(&child));
+        mempool.entries.get_mut(&child.hash()).unwrap().fee = 1000;
+
+        // must pay for the original, its child and its own relay
+        let relay_fee = (INCREMENTAL_FEE * original.size() as Amount).div_ceil(1000);
+        let cheap = pay(&key, txid, 0, 100_000 - 2000 - relay_fee + 1);
+        assert_eq!(mempool.accept(&cheap, &state), Err(MempoolError::ReplacementFee));
+        let replacement = pay(&key, txid, 0, 100_000 - 2000 - relay_fee);
+        mempool.accept(&replacement, &state).unwrap();
+        assert_eq!(mempool.pool.len(), 1);
+        assert!(mempool.pool.contains_key(&replacement.hash()));
+        assert_eq!(mempool.stats().replaced, 2);
+
+        // replacements are off by default
+        let mut mempool = Mempool::new();
+        mempool.accept(&original, &state).unwrap();
+        assert_eq!(mempool.accept(&replacement, &state), Err(MempoolError::Conflict));
+    }
+
+    #[test]
+    fn replacement_evicted_restores_original() {
+        let key = key_pair::random();
+        let (mut state, txid) = funded_state(&key, 2);
+        for index in 0..2 {
+            state.map.get_mut(&OutPoint{ txid, index }).unwrap().balance = 100_000;
+        }
+        let original = pay(&key, txid, 0, 99_000);
+        let other = pay(&key, txid, 1, 50_000);
+        // a better fee rate than the original, but larger and below the other one
+        let mut replacement = pay(&key, txid, 0, 47_500);
+        replacement.transaction.outputs.push(replacement.transaction.outputs[0].clone());
+        let replacement = SignedTrans::new(replacement.transaction, &key);
+        let mut mempool = Mempool::new();
+        mempool.replace_by_fee = true;
+        mempool.max_bytes = original.size() + other.size();
+        mempool.accept(&original, &state).unwrap();
+        mempool.accept(&other, &state).unwrap();
+
+        assert_eq!(mempool.accept(&replacement, &state), Err(MempoolError::Full));
+        assert!(mempool.pool.contains_key(&original.hash()));
+        assert!(mempool.pool.contains_key(&other.hash()));
+        assert_eq!(mempool.stats().bytes, original.size() + other.size());
+        assert_eq!(mempool.stats().replaced, 0);
+    }
+
+    #[test]
+    fn evict_lowest_fee_rate() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 5);
+        let low = pay(&key, txid, 0, 9);
+        let mid = pay(&key, txid, 1, 7);
+        let high = pay(&key, txid, 2, 5);
+        let mut mempool = Mempool::new();
+        mempool.max_bytes = mid.size() + high.size();
+        let now = unix_now();
+
+        mempool.accept(&low, &state).unwrap();
+        mempool.accept(&mid, &state).unwrap();
+        mempool.accept(&high, &state).unwrap();
+        assert!(!mempool.pool.contains_key(&low.hash()));
+        let stats = mempool.stats();
+        assert_eq!((stats.transactions, stats.bytes, stats.evicted), (2, mid.size() + high.size(), 1));
+        let min_fee = (1000 as Amount).div_ceil(low.size() as Amount) + INCREMENTAL_FEE;
+        assert_eq!(stats.min_fee_per_kb, min_fee);
+
+        // below the raised minimum, whatever the room left
+        mempool.remove(&mid);
+        assert_eq!(mempool.accept(&pay(&key, txid, 3, 6), &state), Err(MempoolError::FeeTooLow));
+
+        // the minimum decays once the evictions stop
+        mempool.expire(now + MIN_FEE_HALF_LIFE + 1);
+        assert_eq!(mempool.stats().min_fee_per_kb, min_fee / 2);
+        mempool.expire(now + 2 * MIN_FEE_HALF_LIFE + 1);
+        assert_eq!(mempool.stats().min_fee_per_kb, 0);
+
+        // the new transaction pays the least, it is the one evicted
+        mempool.accept(&mid, &state).unwrap();
+        assert_eq!(mempool.accept(&low, &state), Err(MempoolError::Full));
+        assert_eq!(mempool.stats().evicted, 2);
+    }
+
+    #[test]
+    fn expire_old() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 1);
+        let parent = pay(&key, txid, 0, 9);
+        let child = pay(&key, parent.hash(), 0, 8);
+        let mut mempool = Mempool::new();
+        let now = unix_now();
+        mempool.accept(&parent, &state).unwrap();
+        mempool.add(&child);
+
+        mempool.expire(now + MEMPOOL_EXPIRY - 1);
+        assert_eq!(mempool.pool.len(), 2);
+        // the child can't stay without its parent
+        mempool.expire(now + MEMPOOL_EXPIRY + 1);
+        assert!(mempool.pool.is_empty());
+        let stats = mempool.stats();
+        assert_eq!((stats.bytes, stats.expired), (0, 2));
+    }
+
+    #[test]
+    fn accept_unconfirmed_chain() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 1);
+        let parent = pay(&key, txid, 0, 9);
+        let child = pay(&key, parent.hash(), 0, 8);
+        let grandchild = pay(&key, child.hash(), 0, 7);
+        let mut mempool = Mempool::new();
+
+        // the parents arrive last
+        assert_eq!(mempool.accept(&grandchild, &state), Err(MempoolError::MissingInputs(vec![child.hash()])));
+        mempool.accept(&parent, &state).unwrap();
+        mempool.accept(&child, &state).unwrap();
+        mempool.accept(&grandchild, &state).unwrap();
+        assert_eq!(mempool.ancestors(&grandchild), vec![parent.hash(), child.hash()].into_iter().collect());
+        assert_eq!(mempool.descendants(&parent.hash()).len(), 3);
+        assert_eq!(mempool.fee(&grandchild, &state), Some(1));
+
+        // a pending output is spent only once, and only what exists can be spent
+        assert_eq!(mempool.accept(&pay(&key, parent.hash(), 0, 1), &state), Err(MempoolError::Conflict));
+        assert_eq!(mempool.accept(&pay(&key, parent.hash(), 1, 1), &state),
+                   Err(MempoolError::Invalid(TxError::DoubleSpend)));
+
+        // mined in order, parents first
+        let (trans, _) = mempool.block_template(&state, usize::MAX);
+        let order: Vec<H256> = trans.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), child.hash(), grandchild.hash()]);
+    }
+
+    #[test]
+    fn chain_limits() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 1);
+        let mut mempool = Mempool::new();
+        let mut previous = txid;
+        for _ in 0..MAX_ANCESTORS {
+            let tx = pay(&key, previous, 0, 10);
+            mempool.accept(&tx, &state).unwrap();
+            previous = tx.hash();
+        }
+        assert_eq!(mempool.accept(&pay(&key, previous, 0, 10), &state), Err(MempoolError::TooLongChain));
+    }
+
+    #[test]
+    fn replacement_keeps_its_inputs() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&key, 2);
+        let parent = pay(&key, txid, 0, 10);
+        let original = pay(&key, txid, 1, 10);
+        let mut mempool = Mempool::new();
+        mempool.replace_by_fee = true;
+        mempool.accept(&parent, &state).unwrap();
+        mempool.accept(&original, &state).unwrap();
+
+        // pays more, but adds a pending input the original didn't have
+        let replacement = SignedTrans::new(Transaction{
+            inputs: vec![Input{index: 1, previous_hash: txid}, Input{index: 0, previous_hash: parent.hash()}],
+            outputs: vec![Output{balance: 1, address: H160::hash(key.public_key().as_ref())}],
+        }, &key);
+        assert_eq!(mempool.accept(&replacement, &state), Err(MempoolError::ReplacementInputs));
+    }
+
+    #[test]
+    fn reject_theft() {
+        let owner = key_pair::random();
+        let thief = key_pair::random();
+        let txid = generate_rand_hash256();
+        let mut state = State::new();
+        state.map.insert(OutPoint{ txid, index: 0 }, Output{balance: 5, address: H160::hash(owner.public_key().as_ref())});
+        let mut mempool = Mempool::new();
+
+        let theft = spend(&thief, txid);
+        assert_eq!(mempool.accept(&theft, &state), Err(MempoolError::Invalid(TxError::NotOwner)));
+        assert!(mempool.pool.is_empty());
+
+        let payment = spend(&owner, txid);
+        assert_eq!(mempool.accept(&payment, &state), Ok(()));
+        assert!(mempool.pool.contains_key(&payment.hash()));
+        assert_eq!(state.map.len(), 1);
+    }
+}
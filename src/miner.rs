@@ -3,22 +3,22 @@ use std::time::SystemTime;
 use rand::Rng;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
-use crate::block::Block;
-use crate::crypto::merkle::MerkleTree;
+use crate::block::{Block, merkle_root};
 use crate::signedtrans::SignedTrans;
-use crate::network::message::Message;
+use crate::network::inventory::Inv;
 use crate::mempool::Mempool;
-use crate::crypto::key_pair;
+use crate::validation::validate_block;
+use crate::transaction::coin_base;
 
 
-use log::info;
+use log::{info, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
 use std::thread;
 use ring::signature::{Ed25519KeyPair, KeyPair};
-use crate::crypto::hash::{H160, Hashable};
+use crate::crypto::hash::{H160, H256, Hashable};
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
@@ -41,8 +41,9 @@ pub struct Context {
     mined: u32,
     inserted: u32,
     start_time: SystemTime,
-    key: Ed25519KeyPair,
-    self_address:H160,
+    key: Arc<Ed25519KeyPair>,
+    self_address:H160, // the coinbase pays to this address
+    template: Option<(H256, u64, Vec<SignedTrans>, H256)>, // the tip and mempool changes it was built on, the block content and its merkle root
 }
 
 #[derive(Clone)]
@@ -54,7 +55,8 @@ pub struct Handle {
 pub fn new(
     server: &ServerHandle,
     bc: &Arc<Mutex<Blockchain>>,
-    mp: &Arc<Mutex<Mempool>>
+    mp: &Arc<Mutex<Mempool>>,
+    key: &Arc<Ed25519KeyPair>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -67,8 +69,9 @@ pub fn new(
         mined: 0,
         inserted: 0,
         start_time: SystemTime::now(),
-        key: key_pair::random(),
-        self_address: Default::default()
+        key: Arc::clone(key),
+        self_address: H160::hash(key.public_key().as_ref()),
+        template: None,
     };
 
     let handle = Handle {
@@ -146,45 +149,63 @@ impl Context {
 
             // get parent
             let mut bc = self.bc.lock().unwrap();
-            let mp = self.mp.lock().unwrap().clone().pool;
             let parent = bc.tip();
 
             // get timestamp
             let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
 
-            // get difficulty
-            let difficulty = bc.get_difficulty();
-
-            // generate merkle root
-            let mut trans = Vec::<SignedTrans>::new();
-            for (_,val) in mp.clone() {
-                trans.push(val);
+            // get difficulty, retargeted every few blocks
+            let difficulty = bc.next_difficulty(&parent);
+
+            // the template is only rebuilt when the tip or the mempool changed
+            let mp = self.mp.lock().unwrap();
+            let current = match &self.template {
+                Some((tip, changes, _, _)) => *tip == parent && *changes == mp.changes(),
+                None => false,
+            };
+            if !current {
+                // the coinbase comes first and claims the subsidy plus the fees of the block,
+                // its size doesn't depend on the amount so the room left for transactions is known
+                let height = bc.blocks.get(&parent).unwrap().1 + 1;
+                let coinbase = SignedTrans::coinbase(coin_base(&self.self_address, &parent, 0));
+                let empty = Block::new(parent, 0, difficulty, timestamp, merkle_root(&[]), vec![coinbase]);
+                let room = bc.params.max_block_size.saturating_sub(empty.size());
+
+                // generate merkle root, from the transactions paying the highest fee rate
+                let (mut trans, fees) = mp.block_template(&bc.current_state, room);
+                let reward = bc.params.subsidy(height).saturating_add(fees);
+                let coinbase = SignedTrans::coinbase(coin_base(&self.self_address, &parent, reward));
+                trans.insert(0, coinbase);
+                let root = merkle_root(&trans);
+                self.template = Some((parent, mp.changes(), trans, root));
             }
             drop(mp);
-            let merkle_tree = MerkleTree::new(&trans);
-            let root = merkle_tree.root();
+            let (_, _, trans, root) = self.template.as_ref().unwrap();
 
             // generate nonce
             let nonce = rand::thread_rng().gen::<u32>();
 
-            let blk = Block::new(parent,nonce,difficulty,timestamp,root,trans.clone());
+            let blk = Block::new(parent,nonce,difficulty,timestamp,*root,trans.clone());
 
             self.mined += 1;
             if self.mined % 1000 == 0 {
                 println!("{:?} {}", difficulty, self.mined);
             }
-            if blk.hash() <= difficulty && !trans.is_empty() {
-                for tx in blk.clone().content {
-                    self.mp.lock().unwrap().remove(&tx);
+            if blk.hash() <= difficulty {
+                if let Err(e) = validate_block(&bc, &blk) {
+                    warn!("Mined an invalid block {:?}: {:?}", blk.hash(), e);
+                    self.template = None;
+                    continue;
+                }
+                if bc.insert(&blk).is_err() {
+                    continue;
                 }
-                bc.insert(&blk);
+                // drop the mined transactions and whatever conflicts with them
+                self.mp.lock().unwrap().update(&[], &bc.current_state);
                 self.inserted += 1;
 
-                // broadcast to peers
-                let mut block_vec = Vec::new();
-                block_vec.push(blk.hash());
-                let msg = Message::NewBlockHashes(block_vec);
-                self.server.broadcast(msg);
+                // announce to peers
+                self.server.announce(vec![Inv::Block(blk.hash())]);
 
                 mined_size += serde_json::to_string(&blk).unwrap().len();
                 if self.inserted % 100 == 0 {
@@ -0,0 +1,198 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Most addresses sent in one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+/// Most addresses kept, the ones seen longest ago are forgotten first
+const MAX_ADDRESSES: usize = 4096;
+/// Addresses not seen for this long (in seconds) are not worth connecting to
+const ADDR_HORIZON: u64 = 30 * 24 * 60 * 60;
+/// Seconds before an address is tried again, doubled after each failure
+const RETRY_INTERVAL: u64 = 60;
+const MAX_RETRY_INTERVAL: u64 = 60 * 60;
+
+/// A network endpoint and when it was last known to be up, in seconds since the epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub addr: SocketAddr,
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    last_seen: u64,
+    last_attempt: Option<u64>,
+    failures: u32, // failed connections since the last successful one
+    fixed: bool, // given on the command line, never forgotten
+}
+
+/// Peers we know of, learnt from the command line, from connections and from `Addr` gossip
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Learn an address from a peer, returns true if it was unknown. Addresses nobody can connect to
+    /// or not seen within the horizon are ignored, a time in the future counts as now.
+    pub fn add(&mut self, address: PeerAddress, now: u64) -> bool {
+        if address.addr.ip().is_unspecified() || address.addr.port() == 0
+            || address.last_seen.saturating_add(ADDR_HORIZON) < now {
+            return false;
+        }
+        let last_seen = address.last_seen.min(now);
+        if let Some(entry) = self.entries.get_mut(&address.addr) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return false;
+        }
+        self.entries.insert(address.addr, Entry{ last_seen, last_attempt: None, failures: 0, fixed: false });
+        self.evict();
+        self.entries.contains_key(&address.addr)
+    }
+
+    /// A peer given on the command line, connected to right away so the next attempt waits
+    pub fn add_fixed(&mut self, addr: SocketAddr, now: u64) {
+        let entry = self.entries.entry(addr)
+            .or_insert(Entry{ last_seen: 0, last_attempt: None, failures: 0, fixed: true });
+        entry.fixed = true;
+        entry.last_attempt = Some(now);
+    }
+
+    /// We are talking to the peer listening at `addr`
+    pub fn seen(&mut self, addr: SocketAddr, now: u64) {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return;
+        }
+        let entry = self.entries.entry(addr)
+            .or_insert(Entry{ last_seen: now, last_attempt: None, failures: 0, fixed: false });
+        entry.last_seen = now;
+        entry.failures = 0;
+        self.evict();
+    }
+
+    /// A connection to `addr` failed
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.failures = entry.failures.saturating_add(1);
+        }
+    }
+
+    /// Pick the next address to connect to, not in `exclude`, and record the attempt.
+    /// Fixed peers come first, then the most recently seen ones.
+    pub fn select(&mut self, now: u64, exclude: &HashSet<SocketAddr>) -> Option<SocketAddr> {
+        let addr = self.entries.iter()
+            .filter(|(addr, _)| !exclude.contains(addr))
+            .filter(|(_, entry)| match entry.last_attempt {
+                Some(attempt) => {
+                    let backoff = (RETRY_INTERVAL << entry.failures.min(6)).min(MAX_RETRY_INTERVAL);
+                    now >= attempt.saturating_add(backoff)
+                }
+                None => true,
+            })
+            .max_by_key(|(addr, entry)| (entry.fixed, entry.last_seen, std::cmp::Reverse(**addr)))
+            .map(|(addr, _)| *addr)?;
+        self.entries.get_mut(&addr).unwrap().last_attempt = Some(now);
+        Some(addr)
+    }
+
+    /// Up to `max` of the most recently seen addresses, to answer `GetAddr`
+    pub fn sample(&self, now: u64, max: usize) -> Vec<PeerAddress> {
+        let mut addresses: Vec<PeerAddress> = self.entries.iter()
+            .filter(|(_, entry)| entry.last_seen.saturating_add(ADDR_HORIZON) >= now)
+            .map(|(addr, entry)| PeerAddress{ addr: *addr, last_seen: entry.last_seen })
+            .collect();
+        addresses.sort_by_key(|address| std::cmp::Reverse(address.last_seen));
+        addresses.truncate(max);
+        addresses
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget the addresses seen longest ago while there are too many
+    fn evict(&mut self) {
+        while self.entries.len() > MAX_ADDRESSES {
+            let oldest = self.entries.iter()
+                .filter(|(_, entry)| !entry.fixed)
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(addr, _)| *addr);
+            match oldest {
+                Some(addr) => self.entries.remove(&addr),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn address(port: u16, last_seen: u64) -> PeerAddress {
+        PeerAddress{ addr: SocketAddr::from(([127, 0, 0, 1], port)), last_seen }
+    }
+
+    #[test]
+    fn add_and_sample() {
+        let mut book = AddressBook::new();
+        assert!(book.add(address(6001, NOW - 100), NOW));
+        assert!(!book.add(address(6001, NOW - 10), NOW));
+        assert!(book.add(address(6002, NOW + 1000), NOW));
+        // unreachable or stale addresses are ignored
+        assert!(!book.add(PeerAddress{ addr: "0.0.0.0:6000".parse().unwrap(), last_seen: NOW }, NOW));
+        assert!(!book.add(address(6003, NOW - ADDR_HORIZON - 1), NOW));
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.sample(NOW, 10), vec![address(6002, NOW), address(6001, NOW - 10)]);
+        assert_eq!(book.sample(NOW, 1).len(), 1);
+    }
+
+    #[test]
+    fn select_with_backoff() {
+        let mut book = AddressBook::new();
+        book.add(address(6001, NOW - 100), NOW);
+        book.add(address(6002, NOW - 10), NOW);
+        book.add_fixed(SocketAddr::from(([127, 0, 0, 1], 6000)), NOW);
+
+        // the fixed peer was just tried, the most recently seen comes next
+        let mut exclude = HashSet::new();
+        assert_eq!(book.select(NOW, &exclude), Some(address(6002, 0).addr));
+        exclude.insert(address(6001, 0).addr);
+        assert_eq!(book.select(NOW, &exclude), None);
+
+        // after a failure, the retry waits twice as long
+        book.failed(&address(6002, 0).addr);
+        assert_eq!(book.select(NOW + RETRY_INTERVAL, &HashSet::new()), Some(address(6000, 0).addr));
+        assert_eq!(book.select(NOW + RETRY_INTERVAL, &HashSet::new()), Some(address(6001, 0).addr));
+        assert_eq!(book.select(NOW + RETRY_INTERVAL, &HashSet::new()), None);
+        let exclude: HashSet<SocketAddr> = vec![address(6000, 0).addr, address(6001, 0).addr].into_iter().collect();
+        assert_eq!(book.select(NOW + 2 * RETRY_INTERVAL - 1, &exclude), None);
+        assert_eq!(book.select(NOW + 2 * RETRY_INTERVAL, &exclude), Some(address(6002, 0).addr));
+    }
+
+    #[test]
+    fn oldest_forgotten_first() {
+        let mut book = AddressBook::new();
+        book.add_fixed(SocketAddr::from(([10, 0, 0, 1], 1)), NOW);
+        for i in 0..MAX_ADDRESSES as u64 {
+            book.add(address(1000 + i as u16, NOW - 10_000 + i), NOW);
+        }
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        assert!(!book.entries.contains_key(&address(1000, 0).addr));
+        assert!(book.entries.contains_key(&SocketAddr::from(([10, 0, 0, 1], 1))));
+        book.seen(address(1000, 0).addr, NOW);
+        assert!(book.entries.contains_key(&address(1000, 0).addr));
+        assert_eq!(book.len(), MAX_ADDRESSES);
+    }
+}
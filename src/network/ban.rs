@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long (in seconds) a ban for misbehavior lasts
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+
+/// Connections from and to the IP, or only to the node listening on `port` of it, are refused
+/// until `until`, in seconds since the epoch
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+    pub until: u64,
+    pub reason: String,
+}
+
+/// The IP and port to ban for a node listening on `addr`. Nodes on loopback and private addresses
+/// usually share the IP with others, like a local test network, so only the node is banned.
+/// Elsewhere the whole IP is banned, so a peer can't come back from another port.
+pub fn ban_target(addr: SocketAddr) -> (IpAddr, Option<u16>) {
+    let shared = match addr.ip() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    };
+    (addr.ip(), if shared { Some(addr.port()) } else { None })
+}
+
+/// Banned IPs and nodes, kept by the P2P server
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: HashMap<(IpAddr, Option<u16>), Ban>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban `ip`, or only its `port`, for `duration` seconds, a longer ban already in place is kept
+    pub fn ban(&mut self, ip: IpAddr, port: Option<u16>, now: u64, duration: u64, reason: &str) {
+        let until = now.saturating_add(duration);
+        match self.bans.get(&(ip, port)) {
+            Some(ban) if ban.until >= until => {}
+            _ => {
+                self.bans.insert((ip, port), Ban{ ip, port, until, reason: reason.to_string() });
+            }
+        }
+    }
+
+    /// Lift every ban on `ip`, returns false if it wasn't banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|(banned, _), _| banned != ip);
+        self.bans.len() != before
+    }
+
+    /// Whether the node listening on `addr` is banned, by its IP or by its address
+    pub fn is_banned(&self, addr: &SocketAddr, now: u64) -> bool {
+        [(addr.ip(), None), (addr.ip(), Some(addr.port()))].iter()
+            .any(|key| self.bans.get(key).is_some_and(|ban| ban.until > now))
+    }
+
+    /// The bans still in force, expired ones are dropped
+    pub fn list(&mut self, now: u64) -> Vec<Ban> {
+        self.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<Ban> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|ban| (ban.ip, ban.port));
+        bans
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire() {
+        let mut list = BanList::new();
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        let addr = SocketAddr::new(ip, 6000);
+        let other: SocketAddr = "8.8.4.4:6000".parse().unwrap();
+        list.ban(ip, None, 1000, BAN_DURATION, "invalid block");
+        // a shorter ban doesn't cut the current one
+        list.ban(ip, None, 1000, 10, "undecodable message");
+        assert!(list.is_banned(&addr, 1000 + BAN_DURATION - 1));
+        assert!(!list.is_banned(&addr, 1000 + BAN_DURATION));
+        assert!(!list.is_banned(&other, 1000));
+        assert_eq!(list.list(1000), vec![Ban{ ip, port: None, until: 1000 + BAN_DURATION, reason: "invalid block".to_string() }]);
+        assert!(list.list(1000 + BAN_DURATION).is_empty());
+    }
+
+    #[test]
+    fn unban() {
+        let mut list = BanList::new();
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        list.ban(ip, None, 1000, 60, "manual");
+        list.ban(ip, Some(6000), 1000, 60, "manual");
+        assert!(list.unban(&ip));
+        assert!(!list.unban(&ip));
+        assert!(!list.is_banned(&SocketAddr::new(ip, 6000), 1000));
+    }
+
+    #[test]
+    fn ban_local_node_only() {
+        let mut list = BanList::new();
+        let offender: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let honest: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let (ip, port) = ban_target(offender);
+        assert_eq!(port, Some(6001));
+        list.ban(ip, port, 1000, BAN_DURATION, "invalid block");
+        assert!(list.is_banned(&offender, 1000));
+        assert!(!list.is_banned(&honest, 1000));
+        assert_eq!(ban_target("192.168.1.5:6000".parse().unwrap()).1, Some(6000));
+
+        // a public IP is banned on every port
+        let public: SocketAddr = "8.8.8.8:6001".parse().unwrap();
+        let (ip, port) = ban_target(public);
+        assert_eq!(port, None);
+        list.ban(ip, port, 1000, BAN_DURATION, "invalid block");
+        assert!(list.is_banned(&"8.8.8.8:6002".parse().unwrap(), 1000));
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::crypto::hash::H256;
+
+/// Inventory remembered per peer, the oldest is forgotten first
+const MAX_KNOWN_PER_PEER: usize = 10_000;
+/// Rejected transactions remembered until the next block
+const MAX_REJECTED: usize = 10_000;
+/// Confirmed transactions remembered, announcements of older ones are rare
+const MAX_CONFIRMED: usize = 50_000;
+/// A transaction not delivered within this time can be requested from another peer
+pub const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An object announced and fetched between peers
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Inv {
+    Block(H256),
+    Transaction(H256),
+}
+
+/// A set that forgets its oldest entries past a capacity
+#[derive(Debug)]
+struct RollingSet<T> {
+    items: HashSet<T>,
+    order: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Copy + Eq + Hash> RollingSet<T> {
+    fn new(capacity: usize) -> Self {
+        RollingSet{ items: HashSet::new(), order: VecDeque::new(), capacity }
+    }
+
+    /// Returns false if the item was already there
+    fn insert(&mut self, item: T) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.items.remove(&oldest);
+        }
+        true
+    }
+
+    fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+        self.order.clear();
+    }
+}
+
+/// A transaction requested from a peer, and the other peers that announced it, asked in turn
+/// when the request times out
+#[derive(Debug)]
+struct TxRequest {
+    peer: Option<SocketAddr>, // None once the peer disconnected
+    requested: Instant,
+    alternatives: VecDeque<SocketAddr>,
+}
+
+impl TxRequest {
+    fn pending(&self, now: Instant) -> bool {
+        self.peer.is_some() && now.duration_since(self.requested) < TX_REQUEST_TIMEOUT
+    }
+}
+
+/// What each peer is known to have, what not to fetch again and the transactions requested,
+/// shared by the server and the workers so each object is announced and fetched at most once per peer
+#[derive(Debug)]
+pub struct Inventory {
+    known: HashMap<SocketAddr, RollingSet<Inv>>, // announced by the peer, sent to it or announced to it
+    rejected: RollingSet<H256>, // transactions refused by the mempool since the last block
+    confirmed: RollingSet<H256>, // transactions of recent blocks
+    in_flight: HashMap<H256, TxRequest>,
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Inventory{
+            known: HashMap::new(),
+            rejected: RollingSet::new(MAX_REJECTED),
+            confirmed: RollingSet::new(MAX_CONFIRMED),
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl Inventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember that `peer` has `inv`, returns false if it was already known
+    pub fn add_known(&mut self, peer: SocketAddr, inv: Inv) -> bool {
+        self.known.entry(peer).or_insert_with(|| RollingSet::new(MAX_KNOWN_PER_PEER)).insert(inv)
+    }
+
+    pub fn knows(&self, peer: SocketAddr, inv: &Inv) -> bool {
+        self.known.get(&peer).is_some_and(|known| known.contains(inv))
+    }
+
+    /// The part of `items` to announce to `peer`, which is then known to have them
+    pub fn to_announce(&mut self, peer: SocketAddr, items: &[Inv]) -> Vec<Inv> {
+        items.iter().filter(|inv| self.add_known(peer, **inv)).cloned().collect()
+    }
+
+    /// Transactions announced by `peer` worth requesting from it, the caller already dropped the
+    /// ones it has. Skips the ones the peer announced before, the rejected and confirmed ones,
+    /// and those requested from another peer less than `TX_REQUEST_TIMEOUT` ago, which are
+    /// asked from `peer` if that request times out.
+    pub fn request_transactions(&mut self, peer: SocketAddr, hashes: &[H256], now: Instant) -> Vec<H256> {
+        let mut requested = Vec::new();
+        for hash in hashes {
+            let announced = self.add_known(peer, Inv::Transaction(*hash));
+            if self.rejected.contains(hash) || self.confirmed.contains(hash) {
+                continue;
+            }
+            match self.in_flight.get_mut(hash) {
+                Some(request) if request.pending(now) => {
+                    if announced && !request.alternatives.contains(&peer) {
+                        request.alternatives.push_back(peer);
+                    }
+                    continue;
+                }
+                Some(request) => {
+                    request.alternatives.retain(|addr| *addr != peer);
+                    request.peer = Some(peer);
+                    request.requested = now;
+                }
+                None if announced => {
+                    self.in_flight.insert(*hash, TxRequest{ peer: Some(peer), requested: now, alternatives: VecDeque::new() });
+                }
+                None => continue,
+            }
+            requested.push(*hash);
+        }
+        requested
+    }
+
+    /// Hand the requests that timed out, or whose peer disconnected, to the next peer that
+    /// announced the transaction. Returns the hashes to request from each peer.
+    pub fn retry_transactions(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<H256>)> {
+        let mut retries: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        self.in_flight.retain(|hash, request| {
+            if request.pending(now) {
+                return true;
+            }
+            match request.alternatives.pop_front() {
+                Some(peer) => {
+                    request.peer = Some(peer);
+                    request.requested = now;
+                    retries.entry(peer).or_default().push(*hash);
+                    true
+                }
+                // whoever announces it next gets the request
+                None => false,
+            }
+        });
+        let mut retries: Vec<(SocketAddr, Vec<H256>)> = retries.into_iter().collect();
+        retries.sort_by_key(|(peer, _)| *peer);
+        retries
+    }
+
+    /// A transaction arrived from `peer`, requested or not
+    pub fn received_transaction(&mut self, peer: SocketAddr, hash: H256) {
+        self.add_known(peer, Inv::Transaction(hash));
+        self.in_flight.remove(&hash);
+    }
+
+    /// Don't fetch the transaction again until the next block, which may make it valid
+    pub fn reject(&mut self, hash: H256) {
+        self.rejected.insert(hash);
+    }
+
+    pub fn is_rejected(&self, hash: &H256) -> bool {
+        self.rejected.contains(hash)
+    }
+
+    /// A block joined the chain, its transactions are no longer fetched
+    pub fn connected(&mut self, txids: &[H256]) {
+        for txid in txids {
+            self.confirmed.insert(*txid);
+            self.in_flight.remove(txid);
+        }
+        self.rejected.clear();
+    }
+
+    /// The peer disconnected, its pending requests go to other peers
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.known.remove(&peer);
+        for request in self.in_flight.values_mut() {
+            request.alternatives.retain(|addr| *addr != peer);
+            if request.peer == Some(peer) {
+                request.peer = None;
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_rand_hash256;
+
+    #[test]
+    fn announce_once_per_peer() {
+        let mut inventory = Inventory::new();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let block = Inv::Block(generate_rand_hash256());
+        let tx = Inv::Transaction(generate_rand_hash256());
+
+        // the peer that sent the block doesn't get it announced back
+        inventory.add_known(first, block);
+        assert_eq!(inventory.to_announce(first, &[block, tx]), vec![tx]);
+        assert_eq!(inventory.to_announce(second, &[block, tx]), vec![block, tx]);
+        assert!(inventory.to_announce(first, &[block, tx]).is_empty());
+        assert!(inventory.to_announce(second, &[block, tx]).is_empty());
+
+        inventory.remove_peer(first);
+        assert!(!inventory.knows(first, &block));
+        assert!(inventory.knows(second, &block));
+    }
+
+    #[test]
+    fn request_once() {
+        let mut inventory = Inventory::new();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let hashes = vec![generate_rand_hash256(), generate_rand_hash256()];
+        let now = Instant::now();
+
+        assert_eq!(inventory.request_transactions(first, &hashes, now), hashes);
+        // in flight from the first peer, the second is asked if it doesn't deliver
+        assert!(inventory.request_transactions(second, &hashes, now).is_empty());
+        inventory.received_transaction(first, hashes[0]);
+        assert_eq!(inventory.in_flight(), 1);
+        assert!(inventory.retry_transactions(now).is_empty());
+
+        // the request timed out, the second peer announced it too
+        let later = now + TX_REQUEST_TIMEOUT;
+        assert_eq!(inventory.retry_transactions(later), vec![(second, hashes[1..].to_vec())]);
+        assert!(inventory.request_transactions(first, &hashes, later).is_empty());
+
+        // nobody else announced it, the next announcer gets the request
+        let latest = later + TX_REQUEST_TIMEOUT;
+        assert!(inventory.retry_transactions(latest).is_empty());
+        assert_eq!(inventory.in_flight(), 0);
+        let third: SocketAddr = "127.0.0.1:6003".parse().unwrap();
+        assert_eq!(inventory.request_transactions(third, &hashes[1..], latest), hashes[1..]);
+    }
+
+    #[test]
+    fn retry_when_peer_leaves() {
+        let mut inventory = Inventory::new();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let hash = generate_rand_hash256();
+        let now = Instant::now();
+
+        assert_eq!(inventory.request_transactions(first, &[hash], now), vec![hash]);
+        assert!(inventory.request_transactions(second, &[hash], now).is_empty());
+        inventory.remove_peer(first);
+        assert_eq!(inventory.retry_transactions(now), vec![(second, vec![hash])]);
+    }
+
+    #[test]
+    fn rejected_and_confirmed() {
+        let mut inventory = Inventory::new();
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let rejected = generate_rand_hash256();
+        let confirmed = generate_rand_hash256();
+        let now = Instant::now();
+
+        inventory.reject(rejected);
+        inventory.connected(&[confirmed]);
+        assert!(!inventory.is_rejected(&rejected));
+        inventory.reject(rejected);
+        assert!(inventory.request_transactions(peer, &[rejected, confirmed], now).is_empty());
+
+        // a new block may make the rejected transaction valid
+        inventory.connected(&[]);
+        assert_eq!(inventory.request_transactions(other, &[rejected, confirmed], now), vec![rejected]);
+    }
+
+    #[test]
+    fn forget_oldest() {
+        let mut set = RollingSet::new(2);
+        assert!(set.insert(1));
+        assert!(set.insert(2));
+        assert!(!set.insert(1));
+        assert!(set.insert(3));
+        assert!(!set.contains(&1));
+        assert!(set.contains(&2) && set.contains(&3));
+    }
+}
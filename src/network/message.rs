@@ -1,17 +1,76 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 use crate::crypto::hash::{H160, H256};
-use crate::block::Block;
+use crate::block::{Block, Header};
 use crate::signedtrans::{SignedTrans};
+use super::address_book::PeerAddress;
+
+/// Version of the protocol this node speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version of the protocol we still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const USER_AGENT: &str = concat!("/bitcoin:", env!("CARGO_PKG_VERSION"), "/");
+
+/// What a node announces about itself when a connection opens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: u32,
+    pub genesis: H256, // identifies the network
+    pub best_height: u32,
+    pub user_agent: String,
+    pub listen_addr: SocketAddr, // where the node accepts connections
+}
+
+impl Version {
+    /// Check a peer announcing this version can talk to a node of the network starting at `genesis`
+    pub fn check(&self, genesis: &H256) -> Result<(), String> {
+        if self.genesis != *genesis {
+            return Err(format!("wrong network, genesis {}", self.genesis));
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(format!("obsolete protocol version {}", self.version));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    Version(Version), // first message on a connection, answered by Verack
+    Verack,
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    GetHeaders(Vec<H256>), // block locator, the peer answers with the headers after the fork
+    Headers(Vec<Header>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTrans>),
-    Address(Vec<H160>)
+    Address(Vec<H160>), // wallet addresses
+    GetAddr,
+    Addr(Vec<PeerAddress>), // network endpoints of peers
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_rand_hash256;
+
+    #[test]
+    fn version_check() {
+        let genesis = generate_rand_hash256();
+        let version = Version{
+            version: PROTOCOL_VERSION,
+            genesis,
+            best_height: 10,
+            user_agent: USER_AGENT.to_string(),
+            listen_addr: "127.0.0.1:6000".parse().unwrap(),
+        };
+        assert_eq!(version.check(&genesis), Ok(()));
+        assert!(version.check(&generate_rand_hash256()).is_err());
+        let obsolete = Version{ version: MIN_PROTOCOL_VERSION - 1, ..version };
+        assert!(obsolete.check(&genesis).is_err());
+    }
 }
@@ -1,4 +1,10 @@
+pub mod address_book;
+pub mod ban;
+pub mod inventory;
 pub mod message;
+pub mod orphan;
+pub mod orphan_tx;
 pub mod peer;
 pub mod server;
+pub mod sync;
 pub mod worker;
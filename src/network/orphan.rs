@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+
+/// Most orphan blocks kept at once
+const MAX_ORPHAN_BLOCKS: usize = 750;
+/// Most bytes of orphan blocks kept at once
+const MAX_ORPHAN_BYTES: usize = 32 * 1024 * 1024;
+/// An orphan whose parent hasn't arrived within this time is dropped
+const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// Blocks whose parent is unknown, shared by the workers until the parent arrives.
+/// When full, the oldest orphans are evicted first.
+#[derive(Debug)]
+pub struct OrphanPool {
+    blocks: HashMap<H256, (Block, Instant)>, // orphan hash, the block and when it arrived
+    children: HashMap<H256, Vec<H256>>, // missing parent, the orphans waiting for it
+    bytes: usize,
+    pub max_blocks: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool{
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            bytes: 0,
+            max_blocks: MAX_ORPHAN_BLOCKS,
+            max_bytes: MAX_ORPHAN_BYTES,
+        }
+    }
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep an orphan, returns false if it was already there or is too large to ever fit
+    pub fn insert(&mut self, block: Block, now: Instant) -> bool {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) || block.size() > self.max_bytes {
+            return false;
+        }
+        self.expire(now);
+        self.bytes += block.size();
+        self.children.entry(block.header.parent).or_default().push(hash);
+        self.blocks.insert(hash, (block, now));
+        while self.blocks.len() > self.max_blocks || self.bytes > self.max_bytes {
+            let oldest = *self.blocks.iter().min_by_key(|(_, (_, arrived))| *arrived).unwrap().0;
+            self.remove(&oldest);
+        }
+        self.blocks.contains_key(&hash)
+    }
+
+    /// Drop the orphans older than the expiry time
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self.blocks.iter()
+            .filter(|(_, (_, arrived))| now.duration_since(*arrived) >= ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let (block, _) = self.blocks.remove(hash)?;
+        self.bytes -= block.size();
+        let parent = block.header.parent;
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|child| child != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+        Some(block)
+    }
+
+    /// Remove and return every orphan waiting for `parent`, in arrival order
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    /// Drop every orphan built on `hash`, once it turned out to be invalid
+    pub fn discard_descendants(&mut self, hash: &H256) {
+        let mut parents = vec![*hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.take_children(&parent) {
+                parents.push(child.hash());
+            }
+        }
+    }
+
+    /// The first ancestor of `parent` that is not an orphan, the block to ask peers for
+    pub fn missing_ancestor(&self, parent: &H256) -> H256 {
+        let mut current = *parent;
+        while let Some((block, _)) = self.blocks.get(&current) {
+            current = block.header.parent;
+        }
+        current
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::generate_random_block;
+    use crate::crypto::hash::generate_rand_hash256;
+
+    #[test]
+    fn every_child_is_kept() {
+        let mut pool = OrphanPool::new();
+        let parent = generate_rand_hash256();
+        let first = generate_random_block(&parent);
+        let second = generate_random_block(&parent);
+        let grandchild = generate_random_block(&first.hash());
+        let now = Instant::now();
+        assert!(pool.insert(first.clone(), now));
+        assert!(pool.insert(second.clone(), now));
+        assert!(pool.insert(grandchild.clone(), now));
+        assert!(!pool.insert(first.clone(), now));
+        assert_eq!(pool.missing_ancestor(&grandchild.header.parent), parent);
+
+        let children: Vec<H256> = pool.take_children(&parent).iter().map(|b| b.hash()).collect();
+        assert_eq!(children, vec![first.hash(), second.hash()]);
+        assert!(pool.take_children(&parent).is_empty());
+        assert_eq!(pool.take_children(&first.hash())[0].hash(), grandchild.hash());
+        assert_eq!(pool.blocks.len(), 0);
+        assert_eq!(pool.bytes, 0);
+    }
+
+    #[test]
+    fn oldest_evicted_when_full() {
+        let mut pool = OrphanPool::new();
+        pool.max_blocks = 2;
+        let now = Instant::now();
+        let blocks: Vec<Block> = (0..3).map(|_| generate_random_block(&generate_rand_hash256())).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            assert!(pool.insert(block.clone(), now + Duration::from_secs(i as u64)));
+        }
+        assert_eq!(pool.blocks.len(), 2);
+        assert!(!pool.contains(&blocks[0].hash()));
+        assert!(!pool.children.contains_key(&blocks[0].header.parent));
+
+        // the byte limit holds too
+        pool.max_blocks = 10;
+        pool.max_bytes = blocks[0].size() + blocks[1].size();
+        let large = generate_random_block(&generate_rand_hash256());
+        pool.insert(large.clone(), now + Duration::from_secs(3));
+        assert!(pool.bytes <= pool.max_bytes);
+        assert!(pool.contains(&large.hash()));
+    }
+
+    #[test]
+    fn orphans_expire() {
+        let mut pool = OrphanPool::new();
+        let now = Instant::now();
+        let old = generate_random_block(&generate_rand_hash256());
+        let young = generate_random_block(&generate_rand_hash256());
+        pool.insert(old.clone(), now);
+        pool.insert(young.clone(), now + Duration::from_secs(60));
+        pool.expire(now + ORPHAN_EXPIRY);
+        assert!(!pool.contains(&old.hash()));
+        assert!(pool.contains(&young.hash()));
+    }
+
+    #[test]
+    fn descendants_of_invalid_dropped() {
+        let mut pool = OrphanPool::new();
+        let now = Instant::now();
+        let invalid = generate_random_block(&generate_rand_hash256());
+        let child = generate_random_block(&invalid.hash());
+        let grandchild = generate_random_block(&child.hash());
+        let unrelated = generate_random_block(&generate_rand_hash256());
+        for block in [&child, &grandchild, &unrelated] {
+            pool.insert(block.clone(), now);
+        }
+        pool.discard_descendants(&invalid.hash());
+        assert_eq!(pool.blocks.len(), 1);
+        assert!(pool.contains(&unrelated.hash()));
+    }
+}
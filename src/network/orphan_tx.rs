@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::crypto::hash::{H256, Hashable};
+use crate::signedtrans::SignedTrans;
+
+/// Most orphan transactions kept at once
+const MAX_ORPHAN_TRANSACTIONS: usize = 100;
+/// Larger transactions aren't kept as orphans, a peer can't fill the pool cheaply with them
+const MAX_ORPHAN_TX_SIZE: usize = 100_000;
+/// An orphan whose parents haven't arrived within this time is dropped
+const ORPHAN_TX_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// Transactions spending outputs of transactions we don't have yet, shared by the workers
+/// until the parents arrive. When full, the oldest orphans are evicted first.
+#[derive(Debug)]
+pub struct OrphanTransactions {
+    txs: HashMap<H256, (SignedTrans, Vec<H256>, Instant)>, // orphan hash, the transaction, its missing parents and when it arrived
+    waiting: HashMap<H256, Vec<H256>>, // missing parent, the orphans waiting for it
+    pub max_transactions: usize,
+}
+
+impl Default for OrphanTransactions {
+    fn default() -> Self {
+        OrphanTransactions{
+            txs: HashMap::new(),
+            waiting: HashMap::new(),
+            max_transactions: MAX_ORPHAN_TRANSACTIONS,
+        }
+    }
+}
+
+impl OrphanTransactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep an orphan until its `missing` parents arrive, returns false if it was already there
+    /// or is too large to keep
+    pub fn insert(&mut self, tx: SignedTrans, missing: Vec<H256>, now: Instant) -> bool {
+        let hash = tx.hash();
+        if self.txs.contains_key(&hash) || tx.size() > MAX_ORPHAN_TX_SIZE {
+            return false;
+        }
+        self.expire(now);
+        for parent in missing.iter() {
+            self.waiting.entry(*parent).or_default().push(hash);
+        }
+        self.txs.insert(hash, (tx, missing, now));
+        while self.txs.len() > self.max_transactions {
+            let oldest = *self.txs.iter().min_by_key(|(_, (_, _, arrived))| *arrived).unwrap().0;
+            self.remove(&oldest);
+        }
+        self.txs.contains_key(&hash)
+    }
+
+    /// Drop the orphans older than the expiry time
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self.txs.iter()
+            .filter(|(_, (_, _, arrived))| now.duration_since(*arrived) >= ORPHAN_TX_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<SignedTrans> {
+        let (tx, missing, _) = self.txs.remove(hash)?;
+        for parent in missing {
+            if let Some(siblings) = self.waiting.get_mut(&parent) {
+                siblings.retain(|child| child != hash);
+                if siblings.is_empty() {
+                    self.waiting.remove(&parent);
+                }
+            }
+        }
+        Some(tx)
+    }
+
+    /// Remove and return every orphan waiting for `parent`, in arrival order.
+    /// Those still missing another parent are to be inserted again.
+    pub fn take_children(&mut self, parent: &H256) -> Vec<SignedTrans> {
+        let hashes = self.waiting.remove(parent).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.txs.contains_key(hash)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_rand_hash256;
+    use crate::signedtrans::generate_random_signedtrans;
+
+    #[test]
+    fn promote_when_parents_arrive() {
+        let mut orphans = OrphanTransactions::new();
+        let first = generate_rand_hash256();
+        let second = generate_rand_hash256();
+        let both = generate_random_signedtrans();
+        let one = generate_random_signedtrans();
+        let now = Instant::now();
+        assert!(orphans.insert(both.clone(), vec![first, second], now));
+        assert!(orphans.insert(one.clone(), vec![first], now));
+        assert!(!orphans.insert(one.clone(), vec![first], now));
+
+        let children: Vec<H256> = orphans.take_children(&first).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(children, vec![both.hash(), one.hash()]);
+        // taken out for every parent, not only the one that arrived
+        assert!(orphans.take_children(&second).is_empty());
+        assert!(orphans.txs.is_empty() && orphans.waiting.is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let mut orphans = OrphanTransactions::new();
+        orphans.max_transactions = 2;
+        let now = Instant::now();
+        let txs: Vec<SignedTrans> = (0..3).map(|_| generate_random_signedtrans()).collect();
+        for (i, tx) in txs.iter().enumerate() {
+            assert!(orphans.insert(tx.clone(), vec![generate_rand_hash256()], now + Duration::from_secs(i as u64)));
+        }
+        assert!(!orphans.contains(&txs[0].hash()));
+        assert_eq!(orphans.waiting.len(), 2);
+
+        orphans.expire(now + Duration::from_secs(1) + ORPHAN_TX_EXPIRY);
+        assert!(!orphans.contains(&txs[1].hash()));
+        assert!(orphans.contains(&txs[2].hash()));
+    }
+}
@@ -1,4 +1,5 @@
-use super::message;
+use super::message::{self, Message, Version};
+use crate::crypto::hash::H256;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
@@ -6,8 +7,25 @@ use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
 
+/// Start of every frame, a peer of another network or protocol is noticed on its first frame
+pub const NETWORK_MAGIC: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+/// Largest frame payload accepted by default, a peer can't make us allocate more for one message
+pub const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+/// Magic, payload length and payload checksum
+const FRAME_HEADER: usize = 12;
+
+/// First 4 bytes of the SHA256 of a payload
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    digest.as_ref()[..4].try_into().unwrap()
+}
+
+fn invalid_frame(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
 enum DecodeState {
-    Length,
+    Header,
     Payload,
 }
 
@@ -17,19 +35,37 @@ pub enum ReadResult {
     EOF,
 }
 
-pub struct ReadContext {
-    reader: std::io::BufReader<mio::net::TcpStream>,
+pub struct ReadContext<R: Read = mio::net::TcpStream> {
+    reader: std::io::BufReader<R>,
     buffer: Vec<u8>,
+    header: [u8; FRAME_HEADER],
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
+    max_frame_size: usize,
 }
 
-impl ReadContext {
+impl<R: Read> ReadContext<R> {
+    pub fn new(stream: R, max_frame_size: usize) -> Self {
+        ReadContext {
+            reader: std::io::BufReader::new(stream),
+            buffer: Vec::new(),
+            header: [0; FRAME_HEADER],
+            msg_length: FRAME_HEADER,
+            read_length: 0,
+            state: DecodeState::Header,
+            max_frame_size,
+        }
+    }
+
+    /// Read what the socket has, a frame that breaks the protocol is an InvalidData error
     pub fn read(&mut self) -> std::io::Result<ReadResult> {
-        let bytes_read = self
-            .reader
-            .read(&mut self.buffer[self.read_length..self.msg_length]);
+        let bytes_read = match self.state {
+            DecodeState::Header => self.reader.read(&mut self.header[self.read_length..]),
+            DecodeState::Payload => self
+                .reader
+                .read(&mut self.buffer[self.read_length..self.msg_length]),
+        };
         match bytes_read {
             Ok(0) => {
                 trace!("Detected socket EOF");
@@ -42,26 +78,30 @@ impl ReadContext {
                 if self.read_length == self.msg_length {
                     // buffer filled, process the buffer
                     match self.state {
-                        DecodeState::Length => {
+                        DecodeState::Header => {
+                            if self.header[0..4] != NETWORK_MAGIC {
+                                return Err(invalid_frame(format!("wrong network magic {:02x?}", &self.header[0..4])));
+                            }
                             let message_length =
-                                u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                                u32::from_be_bytes(self.header[4..8].try_into().unwrap()) as usize;
+                            // check before allocating anything
+                            if message_length > self.max_frame_size {
+                                return Err(invalid_frame(format!(
+                                    "frame of {} bytes over the limit of {}", message_length, self.max_frame_size)));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
-                            self.msg_length = message_length as usize;
+                            self.msg_length = message_length;
                             if self.buffer.len() < self.msg_length {
                                 self.buffer.resize(self.msg_length, 0);
                             }
                             trace!("Received message length={}", message_length);
-                            Ok(ReadResult::Continue)
-                        }
-                        DecodeState::Payload => {
-                            let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
-                            self.state = DecodeState::Length;
-                            self.read_length = 0;
-                            self.msg_length = std::mem::size_of::<u32>();
-                            trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
+                            if message_length > 0 {
+                                return Ok(ReadResult::Continue);
+                            }
+                            self.payload()
                         }
+                        DecodeState::Payload => self.payload(),
                     }
                 } else {
                     Ok(ReadResult::Continue)
@@ -70,6 +110,19 @@ impl ReadContext {
             Err(e) => Err(e),
         }
     }
+
+    /// The payload is complete, check it against the header and start on the next frame
+    fn payload(&mut self) -> std::io::Result<ReadResult> {
+        let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
+        if self.header[8..12] != checksum(&new_payload) {
+            return Err(invalid_frame("wrong payload checksum".to_string()));
+        }
+        self.state = DecodeState::Header;
+        self.read_length = 0;
+        self.msg_length = FRAME_HEADER;
+        trace!("Received full message");
+        Ok(ReadResult::Message(new_payload))
+    }
 }
 
 pub enum WriteResult {
@@ -79,34 +132,46 @@ pub enum WriteResult {
 }
 
 enum WriteState {
-    Length,
+    Header,
     Payload,
 }
 
-pub struct WriteContext {
-    writer: std::io::BufWriter<mio::net::TcpStream>,
+pub struct WriteContext<W: Write = mio::net::TcpStream> {
+    writer: std::io::BufWriter<W>,
     pub queue: channel::Receiver<Vec<u8>>,
-    len_buffer: [u8; std::mem::size_of::<u32>()],
+    header_buffer: [u8; FRAME_HEADER],
     msg_buffer: Vec<u8>,
     msg_length: usize,
     written_length: usize,
     state: WriteState,
 }
 
-impl WriteContext {
+impl<W: Write> WriteContext<W> {
+    pub fn new(stream: W, queue: channel::Receiver<Vec<u8>>) -> Self {
+        WriteContext {
+            writer: std::io::BufWriter::new(stream),
+            queue,
+            header_buffer: [0; FRAME_HEADER],
+            msg_buffer: Vec::new(),
+            msg_length: 0,
+            written_length: 0,
+            state: WriteState::Payload,
+        }
+    }
+
     pub fn write(&mut self) -> std::io::Result<WriteResult> {
         loop {
             match self.state {
-                WriteState::Length => {
-                    if self.written_length == std::mem::size_of::<u32>() {
-                        // if the length part has been fully sent
+                WriteState::Header => {
+                    if self.written_length == FRAME_HEADER {
+                        // if the header has been fully sent
                         self.written_length = 0;
                         self.state = WriteState::Payload;
                         continue;
                     } else {
-                        // we are still sending the length part
+                        // we are still sending the header
                         let written = self.writer.write(
-                            &self.len_buffer[self.written_length..std::mem::size_of::<u32>()],
+                            &self.header_buffer[self.written_length..FRAME_HEADER],
                         )?;
                         if written == 0 {
                             return Ok(WriteResult::EOF);
@@ -130,13 +195,15 @@ impl WriteContext {
                             },
                         };
 
-                        // encode the message and the length
+                        // encode the message and the header
                         self.msg_buffer = msg;
                         self.msg_length = self.msg_buffer.len();
-                        self.len_buffer[..4]
+                        self.header_buffer[0..4].copy_from_slice(&NETWORK_MAGIC);
+                        self.header_buffer[4..8]
                             .copy_from_slice(&(self.msg_length as u32).to_be_bytes());
+                        self.header_buffer[8..12].copy_from_slice(&checksum(&self.msg_buffer));
                         self.written_length = 0;
-                        self.state = WriteState::Length;
+                        self.state = WriteState::Header;
                         continue;
                     } else {
                         // we are still sending the payload
@@ -158,29 +225,14 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    max_frame_size: usize,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
     let addr = stream.peer_addr()?;
-    let bufreader = std::io::BufReader::new(reader_stream);
-    let read_ctx = ReadContext {
-        reader: bufreader,
-        buffer: vec![0; std::mem::size_of::<u32>()],
-        msg_length: std::mem::size_of::<u32>(),
-        read_length: 0,
-        state: DecodeState::Length,
-    };
-    let bufwriter = std::io::BufWriter::new(writer_stream);
+    let read_ctx = ReadContext::new(reader_stream, max_frame_size);
     let (write_sender, write_receiver) = channel::channel();
-    let write_ctx = WriteContext {
-        writer: bufwriter,
-        queue: write_receiver,
-        len_buffer: [0; std::mem::size_of::<u32>()],
-        msg_buffer: Vec::new(),
-        msg_length: 0,
-        written_length: 0,
-        state: WriteState::Payload,
-    };
+    let write_ctx = WriteContext::new(writer_stream, write_receiver);
     let handle = Handle {
         write_queue: write_sender,
         addr,
@@ -192,6 +244,9 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        version: None,
+        verack: false,
+        misbehavior: 0,
     };
     Ok((ctx, handle))
 }
@@ -209,6 +264,43 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    pub version: Option<Version>, // what the peer announced in its Version message
+    pub verack: bool, // the peer accepted our Version
+    pub misbehavior: u32, // the peer is banned when it reaches the ban threshold
+}
+
+impl Context {
+    /// Nothing but Version and Verack is exchanged until both sides accepted the other's version
+    pub fn handshake_done(&self) -> bool {
+        self.version.is_some() && self.verack
+    }
+
+    /// Where the peer accepts connections: the address we connected to, or for an incoming peer
+    /// the port announced in its version, on the IP it connected from
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        match (self.direction, &self.version) {
+            (Direction::Incoming, Some(version)) => std::net::SocketAddr::new(self.addr.ip(), version.listen_addr.port()),
+            _ => self.addr,
+        }
+    }
+
+    /// Handle a message received before the handshake is done, Err if the peer must be disconnected
+    pub fn handshake(&mut self, msg: &[u8], genesis: &H256) -> Result<(), String> {
+        match bincode::deserialize(msg) {
+            Ok(Message::Version(version)) if self.version.is_none() => {
+                version.check(genesis)?;
+                self.version = Some(version);
+                self.handle.write(Message::Verack);
+                Ok(())
+            }
+            Ok(Message::Verack) if !self.verack => {
+                self.verack = true;
+                Ok(())
+            }
+            Ok(_) => Err("unexpected message during the handshake".to_string()),
+            Err(e) => Err(format!("undecodable message: {}", e)),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -218,6 +310,10 @@ pub struct Handle {
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
@@ -226,3 +322,193 @@ impl Handle {
         }
     }
 }
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::ErrorKind;
+    use std::rc::Rc;
+
+    /// Hands out one chunk per read, WouldBlock when there is none and EOF on an empty chunk
+    struct FakeReader {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl Read for FakeReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.chunks.pop_front() {
+                None => Err(std::io::Error::new(ErrorKind::WouldBlock, "no data")),
+                Some(mut chunk) => {
+                    let size = chunk.len().min(buf.len());
+                    buf[..size].copy_from_slice(&chunk[..size]);
+                    if size < chunk.len() {
+                        self.chunks.push_front(chunk.split_off(size));
+                    }
+                    Ok(size)
+                }
+            }
+        }
+    }
+
+    /// Takes at most `per_write` bytes per write and blocks every other call
+    struct FakeWriter {
+        written: Rc<RefCell<Vec<u8>>>,
+        per_write: usize,
+        blocked: bool,
+        closed: bool,
+    }
+
+    impl Write for FakeWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.closed {
+                return Ok(0);
+            }
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(std::io::Error::new(ErrorKind::WouldBlock, "full"));
+            }
+            let size = buf.len().min(self.per_write);
+            self.written.borrow_mut().extend_from_slice(&buf[..size]);
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = NETWORK_MAGIC.to_vec();
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&checksum(payload));
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn reader(chunks: Vec<Vec<u8>>) -> ReadContext<FakeReader> {
+        ReadContext::new(FakeReader { chunks: chunks.into() }, 1024)
+    }
+
+    /// Read until the reader would block, collecting the messages
+    fn read_all<R: Read>(ctx: &mut ReadContext<R>) -> std::io::Result<(Vec<Vec<u8>>, bool)> {
+        let mut messages = vec![];
+        loop {
+            match ctx.read() {
+                Ok(ReadResult::Continue) => {}
+                Ok(ReadResult::Message(msg)) => messages.push(msg),
+                Ok(ReadResult::EOF) => return Ok((messages, true)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((messages, false)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write until the queue is empty, retrying when the writer blocks
+    fn write_all<W: Write>(ctx: &mut WriteContext<W>) -> std::io::Result<WriteResult> {
+        loop {
+            match ctx.write() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn read_frames() {
+        let mut stream = frame(b"hello");
+        stream.extend(frame(b""));
+        stream.extend(frame(b"world"));
+        let mut ctx = reader(vec![stream]);
+        let (messages, eof) = read_all(&mut ctx).unwrap();
+        assert_eq!(messages, vec![b"hello".to_vec(), vec![], b"world".to_vec()]);
+        assert!(!eof);
+    }
+
+    #[test]
+    fn read_frame_byte_by_byte() {
+        let bytes = frame(b"split over many reads");
+        let mut ctx = reader(bytes.iter().map(|b| vec![*b]).collect());
+        let (messages, _) = read_all(&mut ctx).unwrap();
+        assert_eq!(messages, vec![b"split over many reads".to_vec()]);
+    }
+
+    #[test]
+    fn read_eof() {
+        let bytes = frame(b"last");
+        let mut ctx = reader(vec![bytes[..5].to_vec(), bytes[5..].to_vec(), vec![]]);
+        let (messages, eof) = read_all(&mut ctx).unwrap();
+        assert_eq!(messages, vec![b"last".to_vec()]);
+        assert!(eof);
+    }
+
+    #[test]
+    fn reject_oversized_frame() {
+        let mut header = NETWORK_MAGIC.to_vec();
+        header.extend_from_slice(&(u32::MAX).to_be_bytes());
+        header.extend_from_slice(&[0; 4]);
+        let mut ctx = reader(vec![header]);
+        let err = read_all(&mut ctx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // nothing was allocated for the announced payload
+        assert!(ctx.buffer.capacity() < 1024);
+
+        let mut ctx = reader(vec![frame(&[0; 1024]), frame(&[0; 1025])]);
+        let err = read_all(&mut ctx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut bytes = frame(b"other network");
+        bytes[0] ^= 0xff;
+        let mut ctx = reader(vec![bytes]);
+        assert_eq!(read_all(&mut ctx).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_bad_checksum() {
+        let mut bytes = frame(b"corrupted");
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let mut ctx = reader(vec![bytes]);
+        assert_eq!(read_all(&mut ctx).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn write_frames() {
+        let written = Rc::new(RefCell::new(vec![]));
+        let writer = FakeWriter { written: Rc::clone(&written), per_write: 3, blocked: true, closed: false };
+        let (sender, receiver) = channel::channel();
+        let mut ctx = WriteContext::new(writer, receiver);
+        let payloads = vec![b"hello".to_vec(), vec![], vec![7; 20000]];
+        for payload in payloads.iter() {
+            sender.send(payload.clone()).unwrap();
+        }
+        assert!(matches!(write_all(&mut ctx).unwrap(), WriteResult::Complete));
+
+        // what was written reads back as the same messages
+        let bytes = written.borrow().clone();
+        let mut read_ctx = ReadContext::new(FakeReader { chunks: vec![bytes].into() }, MAX_FRAME_SIZE);
+        let (messages, _) = read_all(&mut read_ctx).unwrap();
+        assert_eq!(messages, payloads);
+
+        drop(sender);
+        assert!(matches!(write_all(&mut ctx).unwrap(), WriteResult::ChanClosed));
+    }
+
+    #[test]
+    fn write_eof() {
+        let writer = FakeWriter { written: Rc::new(RefCell::new(vec![])), per_write: 3, blocked: true, closed: true };
+        let (sender, receiver) = channel::channel();
+        let mut ctx = WriteContext::new(writer, receiver);
+        sender.send(vec![1; 10000]).unwrap();
+        // the buffered writer reports a closed stream as WriteZero when it flushes
+        match write_all(&mut ctx) {
+            Ok(WriteResult::EOF) => {}
+            Err(e) => assert_eq!(e.kind(), ErrorKind::WriteZero),
+            _ => panic!("a closed stream must end the writer"),
+        }
+    }
+}
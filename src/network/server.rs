@@ -1,22 +1,44 @@
-use super::message;
+use super::message::{self, Message, Version, PROTOCOL_VERSION, USER_AGENT};
+use super::address_book::{AddressBook, PeerAddress};
+use crate::clock::unix_now;
+use super::ban::{ban_target, Ban, BanList, BAN_DURATION, BAN_THRESHOLD};
+use super::inventory::{Inv, Inventory};
+use super::sync::Downloads;
 use super::peer::{self, ReadResult, WriteResult};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// Outgoing connections the connection manager keeps open
+const TARGET_OUTBOUND: usize = 8;
+/// How often the connection manager checks the outgoing connections
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    bc: &Arc<Mutex<Blockchain>>,
+    addr_book: &Arc<Mutex<AddressBook>>,
+    inventory: &Arc<Mutex<Inventory>>,
+    downloads: &Arc<Mutex<Downloads>>,
+    max_frame_size: usize,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        max_frame_size,
     };
     let ctx = Context {
         peers: slab::Slab::new(),
@@ -25,7 +47,15 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
-        _handle: handle.clone(),
+        handle: handle.clone(),
+        genesis: bc.lock().unwrap().genesis(),
+        bc: Arc::clone(bc),
+        addr_book: Arc::clone(addr_book),
+        inventory: Arc::clone(inventory),
+        downloads: Arc::clone(downloads),
+        connecting: HashSet::new(),
+        bans: BanList::new(),
+        max_frame_size,
     };
     Ok((ctx, handle))
 }
@@ -37,7 +67,15 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
-    _handle: Handle,
+    handle: Handle,
+    genesis: H256, // peers must start from the same genesis block
+    bc: Arc<Mutex<Blockchain>>,
+    addr_book: Arc<Mutex<AddressBook>>,
+    inventory: Arc<Mutex<Inventory>>,
+    downloads: Arc<Mutex<Downloads>>, // the blocks requested from a peer that disconnects go to the others
+    connecting: HashSet<std::net::SocketAddr>, // outgoing connections being opened
+    bans: BanList,
+    max_frame_size: usize, // peers sending a larger frame are disconnected
 }
 
 impl Context {
@@ -51,12 +89,64 @@ impl Context {
         Ok(())
     }
 
+    /// What we announce to a new peer
+    fn local_version(&self) -> Version {
+        Version{
+            version: PROTOCOL_VERSION,
+            genesis: self.genesis,
+            best_height: self.bc.lock().unwrap().get_length(),
+            user_agent: USER_AGENT.to_string(),
+            listen_addr: self.addr,
+        }
+    }
+
+    fn remove_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        self.inventory.lock().unwrap().remove_peer(peer.addr);
+        self.downloads.lock().unwrap().peer_disconnected(peer.addr);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
+    /// Add to the misbehavior score of the peer at `addr`, it is banned when the score reaches the threshold
+    fn misbehaving(&mut self, addr: SocketAddr, score: u32, reason: &str) {
+        let peer = match self.peers.iter_mut().find(|(_, peer)| peer.addr == addr) {
+            Some((_, peer)) => peer,
+            None => return,
+        };
+        peer.misbehavior = peer.misbehavior.saturating_add(score);
+        warn!("Peer {} misbehaving ({}), score {}", addr, reason, peer.misbehavior);
+        if peer.misbehavior >= BAN_THRESHOLD {
+            let (ip, port) = ban_target(peer.listen_addr());
+            self.ban(ip, port, BAN_DURATION, reason);
+        }
+    }
+
+    /// Ban an IP, or only the node listening on `port` of it, and disconnect the banned peers
+    fn ban(&mut self, ip: IpAddr, port: Option<u16>, duration: u64, reason: &str) {
+        let now = unix_now();
+        match port {
+            Some(port) => info!("Banning {} for {} seconds: {}", SocketAddr::new(ip, port), duration, reason),
+            None => info!("Banning {} for {} seconds: {}", ip, duration, reason),
+        }
+        self.bans.ban(ip, port, now, duration, reason);
+        let banned: Vec<usize> = self.peer_list.iter()
+            .filter(|peer_id| self.bans.is_banned(&self.peers[**peer_id].listen_addr(), now))
+            .cloned()
+            .collect();
+        for peer_id in banned {
+            self.remove_peer(peer_id);
+        }
+    }
+
     /// Register a TCP stream in the event loop, and initialize peer context.
+    /// Both sides start the handshake by sending their version.
     fn register(
         &mut self,
         stream: net::TcpStream,
         direction: peer::Direction,
     ) -> std::io::Result<peer::Handle> {
+        let version = self.local_version();
         // get a new slot in the connection set
         let vacant = self.peers.vacant_entry();
         let key: usize = vacant.key();
@@ -79,7 +169,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, self.max_frame_size)?;
 
         // register the writer queue
         self.poll.register(
@@ -90,6 +180,7 @@ impl Context {
         )?;
 
         // insert the context and return the handle
+        handle.write(Message::Version(version));
         vacant.insert(ctx);
         // record the key of this peer
         self.peer_list.push(key);
@@ -101,6 +192,9 @@ impl Context {
     fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         // we need to estabilsh a stdlib tcp stream, since we need it to block
         debug!("Establishing connection to peer {}", addr);
+        if self.bans.is_banned(addr, unix_now()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer is banned"));
+        }
         let stream = std::net::TcpStream::connect(addr)?;
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
@@ -113,6 +207,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.bans.is_banned(&addr, unix_now()) {
+            debug!("Refusing incoming connection from banned {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -124,17 +222,121 @@ impl Context {
         Ok(())
     }
 
+    /// Ask other announcers for the transactions a peer didn't deliver in time
+    fn retry_transactions(&mut self) {
+        let retries = self.inventory.lock().unwrap().retry_transactions(Instant::now());
+        for (addr, hashes) in retries {
+            if let Some((_, peer)) = self.peers.iter().find(|(_, peer)| peer.addr == addr) {
+                debug!("Requesting {} transactions from peer {} after a timeout", hashes.len(), addr);
+                peer.handle.write(Message::GetTransactions(hashes));
+            }
+        }
+    }
+
+    /// Open outgoing connections to peers of the address book until there are enough of them.
+    /// Connections are opened in their own thread, the event loop registers them once established.
+    fn maintain_outbound(&mut self) {
+        let outbound = self.peers.iter()
+            .filter(|(_, peer)| matches!(peer.direction, peer::Direction::Outgoing))
+            .count() + self.connecting.len();
+        // the addresses we are already connected to, including the listening address of incoming peers
+        let mut exclude: HashSet<std::net::SocketAddr> = self.connecting.clone();
+        exclude.insert(self.addr);
+        for (_, peer) in self.peers.iter() {
+            exclude.insert(peer.addr);
+            if let Some(version) = &peer.version {
+                exclude.insert(version.listen_addr);
+            }
+        }
+        let now = unix_now();
+        for _ in outbound..TARGET_OUTBOUND {
+            let addr = match self.addr_book.lock().unwrap().select(now, &exclude) {
+                Some(addr) => addr,
+                None => break,
+            };
+            exclude.insert(addr);
+            self.connecting.insert(addr);
+            let handle = self.handle.clone();
+            thread::spawn(move || {
+                debug!("Establishing connection to peer {}", addr);
+                let result = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT);
+                let _ = handle.control_chan.send(ControlSignal::OutgoingConnected(addr, result));
+            });
+        }
+    }
+
     fn process_control(&mut self, req: ControlSignal) -> std::io::Result<()> {
         match req {
+            ControlSignal::OutgoingConnected(addr, result) => {
+                trace!("Processing OutgoingConnected command");
+                self.connecting.remove(&addr);
+                if self.bans.is_banned(&addr, unix_now()) {
+                    return Ok(());
+                }
+                let registered = result
+                    .and_then(net::TcpStream::from_stream)
+                    .and_then(|stream| self.register(stream, peer::Direction::Outgoing));
+                match registered {
+                    Ok(_) => info!("Connected to outgoing peer {}", addr),
+                    Err(e) => {
+                        debug!("Error connecting to peer {}: {}", addr, e);
+                        self.addr_book.lock().unwrap().failed(&addr);
+                    }
+                }
+            }
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
                 let handle = self.connect(&req.addr);
                 req.result_chan.send(handle).unwrap();
             }
+            ControlSignal::Misbehaving(addr, score, reason) => {
+                trace!("Processing Misbehaving command");
+                self.misbehaving(addr, score, &reason);
+            }
+            ControlSignal::Ban(ip, duration) => {
+                trace!("Processing Ban command");
+                self.ban(ip, None, duration, "banned over the API");
+            }
+            ControlSignal::Unban(ip) => {
+                trace!("Processing Unban command");
+                if self.bans.unban(&ip) {
+                    info!("Unbanned {}", ip);
+                }
+            }
+            ControlSignal::GetBans(result_chan) => {
+                trace!("Processing GetBans command");
+                let _ = result_chan.send(self.bans.list(unix_now()));
+            }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    if self.peers[*peer_id].handshake_done() {
+                        self.peers[*peer_id].handle.write(msg.clone());
+                    }
+                }
+            }
+            ControlSignal::Announce(items) => {
+                trace!("Processing Announce command");
+                let mut inventory = self.inventory.lock().unwrap();
+                for peer_id in &self.peer_list {
+                    let peer = &self.peers[*peer_id];
+                    if !peer.handshake_done() {
+                        continue;
+                    }
+                    let mut blocks = vec![];
+                    let mut txes = vec![];
+                    for inv in inventory.to_announce(peer.addr, &items) {
+                        match inv {
+                            Inv::Block(hash) => blocks.push(hash),
+                            Inv::Transaction(hash) => txes.push(hash),
+                        }
+                    }
+                    if !blocks.is_empty() {
+                        peer.handle.write(Message::NewBlockHashes(blocks));
+                    }
+                    if !txes.is_empty() {
+                        peer.handle.write(Message::NewTransactionHashes(txes));
+                    }
                 }
             }
         }
@@ -164,9 +366,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -177,7 +377,32 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    if peer.handshake_done() {
+                        self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                        continue;
+                    }
+                    if let Err(e) = peer.handshake(&m, &self.genesis) {
+                        warn!("Disconnecting peer {}: {}", peer.addr, e);
+                        self.remove_peer(peer_id);
+                        break;
+                    }
+                    // let the workers know about the peer, with what it announced, and the peer about us
+                    // an incoming peer comes from any port, it is known once it tells where it listens
+                    if peer.version.is_some() && self.bans.is_banned(&peer.listen_addr(), unix_now()) {
+                        debug!("Disconnecting banned peer {}", peer.listen_addr());
+                        self.remove_peer(peer_id);
+                        break;
+                    }
+                    if peer.handshake_done() {
+                        let version = peer.version.clone().unwrap();
+                        info!("Handshake with peer {} done: {} version {} height {}",
+                              peer.addr, version.user_agent, version.version, version.best_height);
+                        let now = unix_now();
+                        self.addr_book.lock().unwrap().seen(version.listen_addr, now);
+                        peer.handle.write(Message::Addr(vec![PeerAddress{ addr: self.addr, last_seen: now }]));
+                        let msg = bincode::serialize(&Message::Version(version)).unwrap();
+                        self.new_msg_chan.send((msg, peer.handle.clone())).unwrap();
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -185,11 +410,14 @@ impl Context {
                         trace!("Peer {} finished reading", peer_id);
                         // socket is not ready anymore, stop reading
                         break;
+                    } else if e.kind() == std::io::ErrorKind::InvalidData {
+                        // the stream can't be resynchronized after a bad frame
+                        warn!("Peer {} broke the framing, disconnecting: {}", peer.addr, e);
+                        self.remove_peer(peer_id);
+                        break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -224,9 +452,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +472,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -282,9 +506,15 @@ impl Context {
 
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
+        let mut last_maintenance = Instant::now();
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(MAINTENANCE_INTERVAL))?;
+            if last_maintenance.elapsed() >= MAINTENANCE_INTERVAL {
+                last_maintenance = Instant::now();
+                self.maintain_outbound();
+                self.retry_transactions();
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -367,9 +597,15 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    max_frame_size: usize,
 }
 
 impl Handle {
+    /// The largest frame accepted from a peer, as configured
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
@@ -387,11 +623,53 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Announce blocks and transactions to the peers not known to have them
+    pub fn announce(&self, items: Vec<Inv>) {
+        self.control_chan
+            .send(ControlSignal::Announce(items))
+            .unwrap();
+    }
+
+    /// Report a peer for misbehaving, it is banned once its score reaches the threshold
+    pub fn misbehaving(&self, addr: SocketAddr, score: u32, reason: &str) {
+        self.control_chan
+            .send(ControlSignal::Misbehaving(addr, score, reason.to_string()))
+            .unwrap();
+    }
+
+    /// Ban an IP for `duration` seconds
+    pub fn ban(&self, ip: IpAddr, duration: u64) {
+        self.control_chan
+            .send(ControlSignal::Ban(ip, duration))
+            .unwrap();
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.control_chan
+            .send(ControlSignal::Unban(ip))
+            .unwrap();
+    }
+
+    /// The bans in force
+    pub fn bans(&self) -> Vec<Ban> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetBans(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
+    OutgoingConnected(std::net::SocketAddr, std::io::Result<std::net::TcpStream>),
     BroadcastMessage(message::Message),
+    Announce(Vec<Inv>),
+    Misbehaving(SocketAddr, u32, String),
+    Ban(IpAddr, u64),
+    Unban(IpAddr),
+    GetBans(cbchannel::Sender<Vec<Ban>>),
 }
 
 struct ConnectRequest {
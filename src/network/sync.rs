@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::crypto::hash::H256;
+
+/// Most headers sent in one `Headers` message, a full message means the peer has more
+pub const MAX_HEADERS: usize = 2000;
+/// Blocks requested from one peer at a time, so the download is spread over several peers
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// Missing blocks looked at when handing out requests
+pub const DOWNLOAD_WINDOW: usize = 1024;
+/// A block not delivered within this time is requested from another peer
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Blocks requested from peers during headers-first sync, shared by the workers and the server
+#[derive(Debug, Default)]
+pub struct Downloads {
+    in_flight: HashMap<H256, (SocketAddr, Instant)>, // block hash, the peer it was requested from and when
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand the first `missing` blocks nobody is fetching, or whose request timed out, to `peer`,
+    /// until it has `MAX_BLOCKS_IN_FLIGHT` requests pending
+    pub fn assign(&mut self, peer: SocketAddr, missing: &[H256], now: Instant) -> Vec<H256> {
+        // requests that timed out don't keep the peer busy, they go to whoever asks next
+        let pending = self.in_flight.values()
+            .filter(|(addr, requested)| *addr == peer && now.duration_since(*requested) < BLOCK_TIMEOUT)
+            .count();
+        let mut assigned = Vec::new();
+        for hash in missing {
+            if pending + assigned.len() >= MAX_BLOCKS_IN_FLIGHT {
+                break;
+            }
+            let free = match self.in_flight.get(hash) {
+                Some((_, requested)) => now.duration_since(*requested) >= BLOCK_TIMEOUT,
+                None => true,
+            };
+            if free {
+                self.in_flight.insert(*hash, (peer, now));
+                assigned.push(*hash);
+            }
+        }
+        assigned
+    }
+
+    /// A block arrived, from whichever peer
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    /// The peer disconnected, the blocks requested from it go to other peers
+    pub fn peer_disconnected(&mut self, peer: SocketAddr) {
+        self.in_flight.retain(|_, (addr, _)| *addr != peer);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_rand_hash256;
+
+    #[test]
+    fn spread_over_peers() {
+        let mut downloads = Downloads::new();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let missing: Vec<H256> = (0..40).map(|_| generate_rand_hash256()).collect();
+        let now = Instant::now();
+
+        let to_first = downloads.assign(first, &missing, now);
+        assert_eq!(to_first, missing[..MAX_BLOCKS_IN_FLIGHT]);
+        // the first peer is busy, the second one gets the next blocks
+        assert!(downloads.assign(first, &missing, now).is_empty());
+        let to_second = downloads.assign(second, &missing, now);
+        assert_eq!(to_second, missing[MAX_BLOCKS_IN_FLIGHT..2 * MAX_BLOCKS_IN_FLIGHT]);
+        assert_eq!(downloads.in_flight(), 2 * MAX_BLOCKS_IN_FLIGHT);
+
+        // a delivery frees a slot
+        downloads.received(&missing[0]);
+        let remaining = &missing[1..];
+        assert_eq!(downloads.assign(first, remaining, now), vec![missing[2 * MAX_BLOCKS_IN_FLIGHT]]);
+    }
+
+    #[test]
+    fn timed_out_requests_move() {
+        let mut downloads = Downloads::new();
+        let slow: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let missing = vec![generate_rand_hash256(), generate_rand_hash256()];
+        let now = Instant::now();
+        assert_eq!(downloads.assign(slow, &missing[..1], now), missing[..1]);
+        assert_eq!(downloads.assign(other, &missing, now), missing[1..]);
+        // not before the timeout
+        assert!(downloads.assign(other, &missing[..1], now).is_empty());
+        let later = now + BLOCK_TIMEOUT;
+        downloads.received(&missing[1]);
+        assert_eq!(downloads.assign(other, &missing[..1], later), missing[..1]);
+        assert_eq!(downloads.in_flight(), 1);
+    }
+
+    #[test]
+    fn stalled_peer_gets_new_work() {
+        let mut downloads = Downloads::new();
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let missing: Vec<H256> = (0..2 * MAX_BLOCKS_IN_FLIGHT).map(|_| generate_rand_hash256()).collect();
+        let now = Instant::now();
+        assert_eq!(downloads.assign(peer, &missing, now), missing[..MAX_BLOCKS_IN_FLIGHT]);
+
+        // its requests timed out, the only peer is asked again
+        let later = now + BLOCK_TIMEOUT;
+        assert_eq!(downloads.assign(peer, &missing, later), missing[..MAX_BLOCKS_IN_FLIGHT]);
+
+        // a disconnected peer frees its slots
+        downloads.peer_disconnected(peer);
+        assert_eq!(downloads.in_flight(), 0);
+    }
+}
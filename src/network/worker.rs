@@ -1,19 +1,41 @@
 use super::message::Message;
 use super::peer;
+use super::sync::{Downloads, DOWNLOAD_WINDOW, MAX_HEADERS};
+use super::orphan::OrphanPool;
+use super::orphan_tx::OrphanTransactions;
+use super::inventory::{Inv, Inventory};
+use super::address_book::{AddressBook, MAX_ADDR_PER_MESSAGE};
+use crate::clock::unix_now;
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::block::Block;
 use crate::crypto::hash::{H160, H256, Hashable};
 use crate::blockchain::Blockchain;
 use crate::signedtrans::{SignedTrans};
-use crate::transaction::verify;
-use crate::mempool::Mempool;
+use crate::mempool::{Mempool, MempoolError};
+use crate::validation::{validate_block, validate_header, BlockError};
+use crate::state::{State, TxError};
 
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+
+/// Misbehavior score of a peer sending a block that fails validation, some failures aren't the peer's fault
+fn block_penalty(error: &BlockError) -> u32 {
+    if error.is_invalid() { 100 } else { 0 }
+}
+
+/// Misbehavior score of a peer relaying a transaction the mempool refuses. A spent or unknown input
+/// may only mean the peer saw other transactions or blocks first, and the mempool policy is our own.
+fn tx_penalty(error: &MempoolError) -> u32 {
+    match error {
+        MempoolError::Invalid(TxError::DoubleSpend) => 0,
+        MempoolError::Invalid(_) => 100,
+        _ => 0,
+    }
+}
 
 #[derive(Clone)]
 pub struct Context {
@@ -22,14 +44,24 @@ pub struct Context {
     server: ServerHandle,
     bc: Arc<Mutex<Blockchain>>,
     mem_pool: Arc<Mutex<Mempool>>,
+    downloads: Arc<Mutex<Downloads>>,
+    orphans: Arc<Mutex<OrphanPool>>,
+    orphan_txs: Arc<Mutex<OrphanTransactions>>,
+    addr_book: Arc<Mutex<AddressBook>>,
+    inventory: Arc<Mutex<Inventory>>,
+    max_frame_size: usize, // what peers accept from us, replies are batched well below it
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn new(
     num_worker: usize,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
     bc: &Arc<Mutex<Blockchain>>,
-    mem_pool: &Arc<Mutex<Mempool>>
+    mem_pool: &Arc<Mutex<Mempool>>,
+    addr_book: &Arc<Mutex<AddressBook>>,
+    inventory: &Arc<Mutex<Inventory>>,
+    downloads: &Arc<Mutex<Downloads>>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
@@ -37,6 +69,12 @@ pub fn new(
         server: server.clone(),
         bc: Arc::clone(bc),
         mem_pool: Arc::clone(mem_pool),
+        downloads: Arc::clone(downloads),
+        orphans: Arc::new(Mutex::new(OrphanPool::new())),
+        orphan_txs: Arc::new(Mutex::new(OrphanTransactions::new())),
+        addr_book: Arc::clone(addr_book),
+        inventory: Arc::clone(inventory),
+        max_frame_size: server.max_frame_size(),
     }
 }
 
@@ -52,17 +90,66 @@ impl Context {
         }
     }
 
+    /// Ask `peer` for the next blocks of the best header chain that no other peer is sending
+    fn request_blocks(&self, bc: &Blockchain, peer: &peer::Handle) {
+        let missing = bc.missing_blocks(DOWNLOAD_WINDOW);
+        if missing.is_empty() {
+            return;
+        }
+        let assigned = self.downloads.lock().unwrap().assign(peer.addr(), &missing, Instant::now());
+        if !assigned.is_empty() {
+            peer.write(Message::GetBlocks(assigned));
+        }
+    }
+
+    /// Accept the orphan transactions waiting for `parents`, then the ones waiting for those.
+    /// Returns the accepted ones, the orphans may have come from any peer so nobody is penalized.
+    fn promote_orphans(&self, state: &State, mem_pool: &mut Mempool, inventory: &mut Inventory, mut parents: Vec<H256>) -> Vec<Inv> {
+        let mut accepted = Vec::new();
+        let mut orphans = self.orphan_txs.lock().unwrap();
+        while let Some(parent) = parents.pop() {
+            for tx in orphans.take_children(&parent) {
+                match mem_pool.accept(&tx, state) {
+                    Ok(()) => {
+                        parents.push(tx.hash());
+                        accepted.push(Inv::Transaction(tx.hash()));
+                    }
+                    Err(MempoolError::MissingInputs(missing)) => {
+                        orphans.insert(tx, missing, Instant::now());
+                    }
+                    Err(_) => inventory.reject(tx.hash()),
+                }
+            }
+        }
+        accepted
+    }
+
     fn worker_loop(&self) {
 
-        let mut memory:HashMap<H256,Block>= HashMap::new(); // parent's hash and dangling block
         let mut total_delay:u128 = 0;
         let mut reveived:u128 = 0;
 
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.server.misbehaving(peer.addr(), 100, &format!("undecodable message: {}", e));
+                    continue;
+                }
+            };
             match msg {
+                //the handshake with the peer is done, download the headers of a longer chain and learn its peers
+                Message::Version(version) => {
+                    let blkchain = self.bc.lock().unwrap();
+                    if version.best_height > blkchain.get_length() {
+                        peer.write(Message::GetHeaders(blkchain.block_locator()));
+                    }
+                    peer.write(Message::GetAddr);
+                }
+                //handled by the server during the handshake
+                Message::Verack => {}
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -70,38 +157,72 @@ impl Context {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
-                //For NewBlockHashes, if the hashes are not already in blockchain, you need to ask for them by sending GetBlocks.
+                //For NewBlockHashes, if the headers are unknown, ask for the headers first, the blocks are fetched after them
                 Message::NewBlockHashes(hashes) => {
-                    let mut dic: HashMap<H256, u32> = HashMap::new();
                     let blkchain =self.bc.lock().unwrap();
-
-                    for hash in hashes{
-                        if !blkchain.blocks.contains_key(&hash){
-                            dic.insert(hash, 1);
-                        }
+                    let mut inventory = self.inventory.lock().unwrap();
+                    for hash in hashes.iter() {
+                        inventory.add_known(peer.addr(), Inv::Block(*hash));
                     }
-
-                    if dic.len()>0{
-                        let mut new_blocks: Vec<H256>= Vec::new();
-                        for item in dic {
-                            new_blocks.push(item.0);
+                    drop(inventory);
+                    if hashes.iter().any(|hash| !blkchain.headers.contains_key(hash)) {
+                        peer.write(Message::GetHeaders(blkchain.block_locator()));
+                    }
+                }
+                //send the headers of our longest chain after the fork with the peer's chain
+                Message::GetHeaders(locator) => {
+                    let headers = self.bc.lock().unwrap().headers_after(&locator, MAX_HEADERS);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                //validate and keep the headers, then download the blocks of the best header chain
+                Message::Headers(headers) => {
+                    let mut blkchain = self.bc.lock().unwrap();
+                    let full = headers.len() == MAX_HEADERS;
+                    let mut inventory = self.inventory.lock().unwrap();
+                    for header in headers.iter() {
+                        inventory.add_known(peer.addr(), Inv::Block(header.hash()));
+                    }
+                    drop(inventory);
+                    for header in headers.iter() {
+                        if blkchain.headers.contains_key(&header.hash()) {
+                            continue;
+                        }
+                        if let Err(e) = validate_header(&blkchain, header).and_then(|_| blkchain.insert_header(header)) {
+                            warn!("Rejected header {:?} from {}: {:?}", header.hash(), peer.addr(), e);
+                            // headers that don't connect may come from a reorg, the others are invalid
+                            let penalty = if e == BlockError::UnknownParent { 20 } else { 100 };
+                            self.server.misbehaving(peer.addr(), penalty, &format!("invalid header: {:?}", e));
+                            break;
                         }
-                        peer.write(Message::GetBlocks(new_blocks));
                     }
+                    // a full message means the peer has more headers
+                    if full {
+                        peer.write(Message::GetHeaders(blkchain.block_locator()));
+                    }
+                    self.request_blocks(&blkchain, &peer);
                 }
                 //if the hashes are in blockchain, you can get theses blocks and send them by Blocks message
+                //the blocks are split over several messages so none gets near the frame size limit
                 Message::GetBlocks(hashes) =>{
-                    let mut dic: HashMap<H256, u32> = HashMap::new();
-                    for hash in hashes{
-                        dic.insert(hash, 1);
-                    }
+                    let mut sent: HashSet<H256> = HashSet::new();
                     let mut blocks : Vec<Block> = Vec::new();
+                    let mut size = 0;
                     let blkchain =self.bc.lock().unwrap();
-                    for item in dic{
-                        let hash = item.0;
-                        if blkchain.blocks.contains_key(&hash){
-                            let temp = blkchain.blocks.get(&hash).unwrap().clone();
-                            blocks.push(temp.0);
+                    let mut inventory = self.inventory.lock().unwrap();
+                    for hash in hashes{
+                        if !sent.insert(hash) {
+                            continue;
+                        }
+                        if let Some((block, _, _)) = blkchain.blocks.get(&hash) {
+                            inventory.add_known(peer.addr(), Inv::Block(hash));
+                            if !blocks.is_empty() && size + block.size() > self.max_frame_size / 2 {
+                                peer.write(Message::Blocks(std::mem::take(&mut blocks)));
+                                size = 0;
+                            }
+                            size += block.size();
+                            blocks.push(block.clone());
                         }
                     }
                     if blocks.len()>0{
@@ -110,62 +231,100 @@ impl Context {
                 }
                 //for Blocks, insert the blocks into blockchain if not already in it
                 Message::Blocks(blocks)=>{
-                    //don't find the parents of some blocks in #Block => #GetBlocks
+                    //ask for the missing ancestor of orphans in #GetBlocks
                     //broadcast #NewBlockhashes when received onr from #Block
                     let mut dic_new: HashMap<H256, u32> = HashMap::new();
                     let mut dic_no_parent: HashMap<H256, u32> = HashMap::new();
                     let mut blkchain =self.bc.lock().unwrap();
+                    let old_tip = blkchain.tip();
                     let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                    {
+                        let mut downloads = self.downloads.lock().unwrap();
+                        let mut inventory = self.inventory.lock().unwrap();
+                        for block in blocks.iter() {
+                            downloads.received(&block.hash());
+                            inventory.add_known(peer.addr(), Inv::Block(block.hash()));
+                        }
+                    }
 
                     for block in blocks.iter() {
-                        if !blkchain.blocks.contains_key(&block.hash()){
-                            let new_block_parent = &block.header.parent;
-                            memory.insert(*new_block_parent,block.clone());
-                            total_delay += ts.as_millis() - block.header.get_create_time();
-                            reveived += 1;
-                            // PoW validity check
-                            if block.hash() <= block.header.difficulty {
-                                // Parent check
-                                if blkchain.blocks.contains_key(new_block_parent) {
-                                    if block.header.difficulty!= blkchain.blocks.get(new_block_parent).unwrap().0.header.difficulty {
-                                        continue;
-                                    }
-                                    let mut pool = self.mem_pool.lock().unwrap();
-                                    let signed_tx = block.content.clone();
-                                    for tx in signed_tx{
-                                        pool.remove(&tx);
-                                    }
-                                    // block.hash() < blkchain.blockchain.get(new_block_parent).unwrap().header.difficulty {
-                                    blkchain.insert(&block.clone());
-                                    memory.remove(&block.header.parent);
-                                    dic_new.insert(block.hash(), 1);
+                        if blkchain.blocks.contains_key(&block.hash()) || self.orphans.lock().unwrap().contains(&block.hash()) {
+                            continue;
+                        }
+                        total_delay += ts.as_millis().saturating_sub(block.header.get_create_time());
+                        reveived += 1;
+                        match validate_block(&blkchain, block) {
+                            Ok(()) => {}
+                            Err(BlockError::UnknownParent) => {
+                                // keep the orphan until its parent arrives, blocks on the header chain are downloaded anyway
+                                let mut orphans = self.orphans.lock().unwrap();
+                                orphans.insert(block.clone(), Instant::now());
+                                let missing = orphans.missing_ancestor(&block.header.parent);
+                                if !blkchain.headers.contains_key(&missing) {
+                                    dic_no_parent.insert(missing, 1);
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("Rejected block {:?}: {:?}", block.hash(), e);
+                                blkchain.reject(&block.hash(), &e);
+                                if block_penalty(&e) > 0 {
+                                    self.server.misbehaving(peer.addr(), block_penalty(&e), &format!("invalid block: {:?}", e));
+                                }
+                                continue;
+                            }
+                        }
+                        if blkchain.insert(block).is_err() {
+                            continue;
+                        }
+                        self.inventory.lock().unwrap().connected(&block.content.iter().map(|tx| tx.hash()).collect::<Vec<_>>());
+                        dic_new.insert(block.hash(), 1);
 
-                                    // Orphan block handler: insert validated blocks stored in memory
-                                    let mut inserted: H256 = block.hash();
-                                    while memory.contains_key(&inserted) {
-                                        let next_insert = memory.get(&inserted).unwrap().clone();
-                                        let mut pool = self.mem_pool.lock().unwrap();
-                                        let signed_tx = block.content.clone();
-                                        for tx in signed_tx{
-                                            pool.remove(&tx);
-                                        }
-                                        blkchain.insert(&next_insert.clone());
-                                        memory.remove(&inserted);
-                                        inserted = next_insert.hash();
-                                        dic_new.insert(inserted, 1);
-                                    }
-                                } else {
-                                    dic_no_parent.insert(*new_block_parent, 1);
+                        // Orphan block handler: connect every orphan descending from the new block
+                        let mut parents = vec![block.hash()];
+                        while let Some(parent) = parents.pop() {
+                            let children = self.orphans.lock().unwrap().take_children(&parent);
+                            for child in children {
+                                let inserted = validate_block(&blkchain, &child)
+                                    .and_then(|_| blkchain.insert(&child).map(|_| ()));
+                                if let Err(e) = inserted {
+                                    // the orphan may have come from another peer, nobody is penalized
+                                    warn!("Rejected block {:?}: {:?}", child.hash(), e);
+                                    blkchain.reject(&child.hash(), &e);
+                                    self.orphans.lock().unwrap().discard_descendants(&child.hash());
+                                    continue;
                                 }
+                                self.inventory.lock().unwrap().connected(&child.content.iter().map(|tx| tx.hash()).collect::<Vec<_>>());
+                                dic_new.insert(child.hash(), 1);
+                                parents.push(child.hash());
                             }
-                        }  
+                        }
+                    }
+                    // bring the mempool to the new tip, once for all the blocks, and accept the orphans
+                    // whose parents got confirmed
+                    let mut promoted = Vec::new();
+                    if blkchain.tip() != old_tip {
+                        let disconnected = blkchain.disconnected_since(&old_tip);
+                        let mut mem_pool = self.mem_pool.lock().unwrap();
+                        let evicted = mem_pool.update(&disconnected, &blkchain.current_state);
+                        if !disconnected.is_empty() || !evicted.is_empty() {
+                            info!("Reorganized {} blocks, evicted {} transactions from the mempool", disconnected.len(), evicted.len());
+                        }
+                        let confirmed: Vec<H256> = dic_new.keys()
+                            .flat_map(|hash| blkchain.blocks[hash].0.content.iter().map(|tx| tx.hash()))
+                            .collect();
+                        let mut inventory = self.inventory.lock().unwrap();
+                        promoted = self.promote_orphans(&blkchain.current_state, &mut mem_pool, &mut inventory, confirmed);
+                    }
+                    if !promoted.is_empty() {
+                        self.server.announce(promoted);
                     }
                     if dic_new.len()>0{
-                        let mut new_hashes: Vec<H256> = Vec::new();
+                        let mut new_blocks: Vec<Inv> = Vec::new();
                         for item in dic_new {
-                            new_hashes.push(item.0);
+                            new_blocks.push(Inv::Block(item.0));
                         }
-                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                        self.server.announce(new_blocks);
                     }
                     if dic_no_parent.len()>0{
                         let mut no_parents :Vec::<H256> = Vec::new();
@@ -174,6 +333,8 @@ impl Context {
                         }
                         peer.write(Message::GetBlocks(no_parents));
                     }
+                    // keep this peer busy while blocks of the best header chain are missing
+                    self.request_blocks(&blkchain, &peer);
                     // if reveived>0{
                     //     println!("avg delay:{:?}/{:?}={:?}", total_delay, blkchain.get_block_num(), total_delay / reveived);
                     // }
@@ -185,11 +346,16 @@ impl Context {
 
                     let mut new_tx_hashes:Vec<H256> = Vec::new();
                     let mem_pool = self.mem_pool.lock().unwrap();
+                    let mut inventory = self.inventory.lock().unwrap();
                     for hash in tx_hash{
-                        if !mem_pool.pool.contains_key(&hash){
+                        if mem_pool.pool.contains_key(&hash){
+                            inventory.add_known(peer.addr(), Inv::Transaction(hash));
+                        } else {
                             new_tx_hashes.push(hash);
                         }
                     }
+                    // skip the ones rejected, confirmed or already requested
+                    let new_tx_hashes = inventory.request_transactions(peer.addr(), &new_tx_hashes, Instant::now());
                     if !new_tx_hashes.is_empty(){
                         peer.write(Message::GetTransactions(new_tx_hashes));
                     }
@@ -201,9 +367,11 @@ impl Context {
 
                     let mut new_tx:Vec<SignedTrans> = Vec::new();
                     let mem_pool = self.mem_pool.lock().unwrap();
+                    let mut inventory = self.inventory.lock().unwrap();
                     // let pool = mem_pool.get_pool().clone();
                     for hash in tx_hash{
                         if mem_pool.pool.contains_key(&hash){
+                            inventory.add_known(peer.addr(), Inv::Transaction(hash));
                             let signed_tx = mem_pool.pool.get(&hash).unwrap().clone();
                             new_tx.push(signed_tx);
                         }
@@ -214,34 +382,73 @@ impl Context {
                 }
 
                 Message::Transactions(txes) => {
-                    println!("received Transaction: {:?} trans {:?} to {:?}",
-                             H160::hash(&txes[0].public_key),
-                             txes[0].transaction.outputs[0].balance,
-                             txes[0].transaction.outputs[0].address);
+                    if let Some((tx, output)) = txes.first().and_then(|tx| Some((tx, tx.transaction.outputs.first()?))) {
+                        println!("received Transaction: {:?} trans {:?} to {:?}",
+                                 H160::hash(&tx.public_key), output.balance, output.address);
+                    }
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
-                    let mem_pool = self.mem_pool.lock().unwrap().clone();
                     let mut new_tx_hashes = Vec::new();
-                    let mut chain = self.bc.lock().unwrap();
-                    // let mut pool = mem_pool.get_pool();
-                    let pool = mem_pool.pool.clone();
+                    let chain = self.bc.lock().unwrap();
+                    let mut mem_pool = self.mem_pool.lock().unwrap();
+                    let mut inventory = self.inventory.lock().unwrap();
+                    let mut missing_parents = Vec::new();
                     for tx in txes{
-                        if !pool.contains_key(&tx.hash()){
-                            let pub_key = tx.get_public_key();
-                            let trans = tx.get_tx();
-                            let sig = tx.get_sig();
-                            let is_verified = verify(&trans, &pub_key, &sig);
-                            let is_over_spend = trans.output_val() > trans.input_val();
-                            if is_verified && !(is_over_spend) {
-                                let buf = tx.clone();
-                                self.mem_pool.lock().unwrap().pool.insert(tx.hash(), buf);
-                                new_tx_hashes.push(tx.hash());
-                                chain.update_state(&tx, self.mem_pool.lock().unwrap().clone().pool.len());
+                        inventory.received_transaction(peer.addr(), tx.hash());
+                        if !mem_pool.pool.contains_key(&tx.hash()) && !inventory.is_rejected(&tx.hash())
+                            && !self.orphan_txs.lock().unwrap().contains(&tx.hash()) {
+                            // check against the ledger and the pending transactions, the state changes once the transaction is mined
+                            match mem_pool.accept(&tx, &chain.current_state) {
+                                Ok(()) => {
+                                    new_tx_hashes.push(Inv::Transaction(tx.hash()));
+                                    new_tx_hashes.extend(self.promote_orphans(&chain.current_state, &mut mem_pool, &mut inventory, vec![tx.hash()]));
+                                    chain.print_state(mem_pool.pool.len());
+                                }
+                                // keep it until its parents arrive, from the peer that must have them
+                                Err(MempoolError::MissingInputs(missing)) => {
+                                    let now = Instant::now();
+                                    if self.orphan_txs.lock().unwrap().insert(tx, missing.clone(), now) {
+                                        missing_parents.extend(inventory.request_transactions(peer.addr(), &missing, now));
+                                    }
+                                }
+                                Err(e) => {
+                                    inventory.reject(tx.hash());
+                                    if tx_penalty(&e) > 0 {
+                                        self.server.misbehaving(peer.addr(), tx_penalty(&e), &format!("invalid transaction: {:?}", e));
+                                    }
+                                }
                             }
                         }
                     }
+                    drop(inventory);
+                    drop(mem_pool);
                     drop(chain);
+                    if !missing_parents.is_empty() {
+                        peer.write(Message::GetTransactions(missing_parents));
+                    }
                     if !new_tx_hashes.is_empty() {
-                        self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
+                        self.server.announce(new_tx_hashes);
+                    }
+                }
+
+                Message::GetAddr => {
+                    let addresses = self.addr_book.lock().unwrap().sample(unix_now(), MAX_ADDR_PER_MESSAGE);
+                    if !addresses.is_empty() {
+                        peer.write(Message::Addr(addresses));
+                    }
+                }
+
+                //remember the addresses, and pass on a few new ones so they spread through the network
+                Message::Addr(addresses) => {
+                    if addresses.len() > MAX_ADDR_PER_MESSAGE {
+                        self.server.misbehaving(peer.addr(), 20, &format!("{} addresses in one message", addresses.len()));
+                        continue;
+                    }
+                    let now = unix_now();
+                    let mut book = self.addr_book.lock().unwrap();
+                    let new: Vec<_> = addresses.into_iter().filter(|address| book.add(*address, now)).collect();
+                    drop(book);
+                    if !new.is_empty() && new.len() <= 10 {
+                        self.server.broadcast(Message::Addr(new));
                     }
                 }
 
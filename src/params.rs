@@ -0,0 +1,89 @@
+use crate::crypto::hash::{H256, U256};
+use crate::transaction::Amount;
+
+/// Number of base units in one coin
+pub const COIN: Amount = 100_000_000;
+
+/// Consensus parameters, every node of a network must use the same ones
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// value created by the coinbase of the first blocks
+    pub initial_subsidy: Amount,
+    /// the subsidy is halved every `halving_interval` blocks
+    pub halving_interval: u32,
+    /// largest serialized size of a block, in bytes
+    pub max_block_size: usize,
+    /// the target is recomputed every `retarget_interval` blocks
+    pub retarget_interval: u32,
+    /// block time the target aims at, in milliseconds
+    pub target_block_time: u64,
+    /// easiest target a retarget may reach
+    pub pow_limit: H256,
+    /// (block hash, snapshot hash) of the UTXO snapshot a node may start from instead of the genesis
+    pub assume_utxo: Option<(H256, H256)>,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            initial_subsidy: 50 * COIN,
+            halving_interval: 210_000,
+            max_block_size: 1_000_000,
+            retarget_interval: 100,
+            target_block_time: 10_000,
+            pow_limit: U256::from_compact(0x207f_ffff).unwrap().into(),
+            assume_utxo: None,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Value a coinbase may create at the given height, on top of the fees of its block
+    pub fn subsidy(&self, height: u32) -> Amount {
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= Amount::BITS {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+
+    /// Scale a target by the time the last blocks took against the time they should have taken,
+    /// by at most a factor 4 either way and no easier than `pow_limit`. The result is rounded to
+    /// what a compact target can hold.
+    pub fn retarget(&self, target: H256, actual: u128, expected: u128) -> H256 {
+        let expected = U256::from(expected.max(1));
+        let actual = U256::from(actual).clamp(expected / U256::from(4u64), expected * U256::from(4u64));
+        // target * actual / expected, split so only the quotient part can overflow
+        let (quotient, rem) = U256::from(target).div_rem(expected);
+        let scaled = quotient.saturating_mul(actual).saturating_add(rem * actual / expected);
+        U256::from_compact(scaled.to_compact()).unwrap().min(U256::from(self.pow_limit)).into()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halving() {
+        let params = ChainParams { initial_subsidy: 50 * COIN, halving_interval: 10, ..Default::default() };
+        assert_eq!(params.subsidy(0), 50 * COIN);
+        assert_eq!(params.subsidy(9), 50 * COIN);
+        assert_eq!(params.subsidy(10), 25 * COIN);
+        assert_eq!(params.subsidy(25), 12 * COIN + COIN / 2);
+        assert_eq!(params.subsidy(10 * 63), (50 * COIN) >> 63);
+        assert_eq!(params.subsidy(10 * 64), 0);
+        assert_eq!(params.subsidy(u32::MAX), 0);
+    }
+
+    #[test]
+    fn retarget_capped_at_limit() {
+        let limit = U256::from_compact(0x1d00_ffff).unwrap();
+        let params = ChainParams { pow_limit: limit.into(), ..Default::default() };
+        // blocks four times too slow can't make the target easier than the limit
+        assert_eq!(params.retarget(limit.into(), 4000, 1000), H256::from(limit));
+        let half = limit / U256::from(2u64);
+        assert_eq!(params.retarget(half.into(), 4000, 1000), H256::from(limit));
+        assert_eq!(U256::from(params.retarget(limit.into(), 500, 1000)), half);
+    }
+}
@@ -1,8 +1,7 @@
 use serde::{Serialize, Deserialize};
-use crate::transaction::{Transaction, generate_random_transaction, sign};
+use crate::transaction::{Transaction, sign};
 use crate::crypto::hash::{H256, Hashable};
-use crate::crypto::key_pair;
-use ring::{digest, signature::KeyPair};
+use ring::{digest, signature::{Ed25519KeyPair, KeyPair}};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTrans {
@@ -12,31 +11,48 @@ pub struct SignedTrans {
 }
 
 impl SignedTrans{
+    /// Sign a transaction with the given key pair
+    pub fn new(transaction: Transaction, key: &Ed25519KeyPair) -> SignedTrans {
+        let signature = sign(&transaction, key);
+        let public_key = key.public_key().as_ref().to_vec();
+        SignedTrans{ transaction, signature, public_key }
+    }
+
+    /// Wrap a coinbase transaction, which spends no outputs and so carries no signature
+    pub fn coinbase(transaction: Transaction) -> SignedTrans {
+        SignedTrans{ transaction, signature: Vec::new(), public_key: Vec::new() }
+    }
+
     pub fn get_tx(&self) -> Transaction{self.clone().transaction}
     pub fn get_sig(&self) -> Vec<u8>{self.clone().signature}
     pub fn get_public_key(&self) -> Vec<u8>{self.clone().public_key}
-}
 
-impl Hashable for SignedTrans {
-    fn hash(&self) -> H256 {
-        //unimplemented!()
+    /// Serialized size in bytes, what the transaction takes in a block
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(&self).unwrap() as usize
+    }
+
+    /// Hash of the transaction together with its signature and public key
+    pub fn witness_hash(&self) -> H256 {
         let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
         let mut cat = digest::Context::new(&digest::SHA256);
         cat.update(&encoded);
         let fin = cat.finish();
-        let val = <H256>::from(fin);
-        val
+        <H256>::from(fin)
     }
 }
 
+/// A signed transaction is identified by its transaction id
+impl Hashable for SignedTrans {
+    fn hash(&self) -> H256 {
+        self.transaction.hash()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
 pub fn generate_random_signedtrans() -> SignedTrans{
+    use crate::crypto::key_pair;
+    use crate::transaction::generate_random_transaction;
     let key = key_pair::random();
-    let t = generate_random_transaction();
-    let s = sign(&t, &key);
-    let p = key.public_key().as_ref().to_vec();
-    SignedTrans{
-        transaction: t,
-        signature: s,
-        public_key: p,
-    }
+    SignedTrans::new(generate_random_transaction(), &key)
 }
@@ -0,0 +1,51 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{OutPoint, Output};
+
+/// The UTXO set after a block, so a node can start from that block instead of replaying the chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub height: u32, // height of the base block, the last of `blocks`
+    pub work: H256, // cumulative work of the chain up to the base block
+    pub blocks: Vec<Block>, // the base block and the ancestors the next retargets look at, oldest first
+    pub utxos: Vec<(OutPoint, Output)>, // sorted by outpoint
+}
+
+/// Why a snapshot can't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// the chain parameters don't expect a snapshot of this block
+    UnknownBase,
+    /// the snapshot doesn't match the hash expected for its block
+    HashMismatch,
+    /// no base block, or the blocks don't form a chain ending with the base block
+    BrokenChain,
+}
+
+/// Commits to the base block, its height and work, and every unspent output
+impl Hashable for Snapshot {
+    fn hash(&self) -> H256 {
+        let serialized = bincode::serialize(&(self.base(), self.height, self.work, &self.utxos)).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &serialized).into()
+    }
+}
+
+impl Snapshot {
+    /// Hash of the block the UTXO set belongs to
+    pub fn base(&self) -> H256 {
+        self.blocks.last().map(|block| block.hash()).unwrap_or_default()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Snapshot> {
+        let data = fs::read(path)?;
+        bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, bincode::serialize(self).unwrap())
+    }
+}
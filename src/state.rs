@@ -1,38 +1,205 @@
 use serde::{Serialize,Deserialize};
 use std::collections::HashMap;
-use crate::crypto::hash::H256;
+use crate::crypto::hash::{H160, Hashable};
 use crate::signedtrans::SignedTrans;
-use crate::transaction::{Input, Output};
+use crate::transaction::{Amount, Input, OutPoint, Output, Transaction, verify};
+
+/// Why a transaction can't be executed on a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// only coinbase transactions create coins
+    NoInputs,
+    /// a coinbase is only valid as the first transaction of a block
+    Coinbase,
+    InvalidSignature,
+    /// the same output is spent twice by the transaction
+    DuplicateInput,
+    /// an input is already spent or never existed
+    DoubleSpend,
+    /// the public key doesn't hash to the address of a spent output
+    NotOwner,
+    /// outputs are worth more than inputs
+    Overspend,
+    /// the sum of input or output values doesn't fit in an Amount
+    ValueOverflow,
+}
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State{
-    pub map: HashMap<H256, Output>, // txID -> Output
-    pub sig: HashMap<H256, SignedTrans>  // txID -> pubKey
+    pub map: HashMap<OutPoint, Output>, // (txID, output index) -> unspent Output
 }
 
 impl State{
     pub fn new() -> Self{
         State{
             map: HashMap::new(),
-            sig: HashMap::new()
         }
     }
 
-    pub fn is_double_spend(&self, data:Input) -> bool{
-        let hash = data.get_hash();
-        let flag;
-        if self.map.contains_key(&hash) {
-            let tx_out = self.map.get(&hash).unwrap().clone();
-            let val = tx_out.get_val();
-            let in_val = data.get_val();
-            if val == in_val{
-                flag = false;
-            }else{
-                flag = true;
-            }
-        } else {
-            flag = true;
+    /// Execute a transaction: spend the outputs it consumes and add the outputs it creates
+    pub fn apply(&mut self, sigtrans: &SignedTrans) {
+        let transaction = &sigtrans.transaction;
+        let txid = transaction.hash();
+        for tx_in in transaction.inputs.iter() {
+            self.map.remove(&tx_in.outpoint());
+        }
+        for (index, out) in transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint{ txid, index: index as u32 };
+            self.map.insert(outpoint, out.clone());
+        }
+    }
+
+    /// Check a transaction against this state without executing it:
+    /// the signature, that every input is unspent, and that outputs don't exceed inputs.
+    /// Returns the fee, the value of the inputs not claimed by the outputs.
+    pub fn check(&self, sigtrans: &SignedTrans) -> Result<Amount, TxError> {
+        let transaction = &sigtrans.transaction;
+        if transaction.inputs.is_empty() {
+            return Err(TxError::NoInputs);
+        }
+        if transaction.is_coinbase() {
+            return Err(TxError::Coinbase);
+        }
+        if !verify(transaction, &sigtrans.public_key, &sigtrans.signature) {
+            return Err(TxError::InvalidSignature);
+        }
+        // an output can only be spent once inside the same transaction
+        if transaction.input_outpoints().len() != transaction.inputs.len() {
+            return Err(TxError::DuplicateInput);
+        }
+        if transaction.inputs.iter().any(|input| self.is_double_spend(input)) {
+            return Err(TxError::DoubleSpend);
+        }
+        if !self.is_owner(transaction, &sigtrans.public_key) {
+            return Err(TxError::NotOwner);
         }
-        flag
+        let input_val = self.input_val(transaction).ok_or(TxError::ValueOverflow)?;
+        let output_val = transaction.output_val().ok_or(TxError::ValueOverflow)?;
+        if output_val > input_val {
+            return Err(TxError::Overspend);
+        }
+        Ok(input_val - output_val)
+    }
+
+    pub fn is_valid(&self, sigtrans: &SignedTrans) -> bool {
+        self.check(sigtrans).is_ok()
     }
-}
\ No newline at end of file
+
+    /// The input is spending an output that is not in the UTXO set
+    pub fn is_double_spend(&self, data:&Input) -> bool{
+        !self.map.contains_key(&data.outpoint())
+    }
+
+    /// Every output spent by the transaction belongs to the address of the signing key
+    pub fn is_owner(&self, transaction: &Transaction, public_key: &[u8]) -> bool {
+        let address = H160::hash(public_key);
+        transaction.inputs.iter()
+            .filter_map(|input| self.map.get(&input.outpoint()))
+            .all(|out| out.address == address)
+    }
+
+    /// Total value of the outputs a transaction spends, unknown inputs count as zero.
+    /// None if the sum overflows.
+    pub fn input_val(&self, transaction: &Transaction) -> Option<Amount> {
+        transaction.inputs.iter()
+            .filter_map(|input| self.map.get(&input.outpoint()))
+            .try_fold(0, |sum: Amount, out| sum.checked_add(out.balance))
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::{H256, generate_rand_hash160, generate_rand_hash256};
+    use crate::crypto::key_pair;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn transaction(key: &Ed25519KeyPair, inputs: Vec<Input>, balances: Vec<Amount>) -> SignedTrans {
+        let outputs = balances.into_iter()
+            .map(|balance| Output{balance, address: generate_rand_hash160()})
+            .collect();
+        let t = Transaction{ inputs, outputs };
+        SignedTrans::new(t, key)
+    }
+
+    fn address(key: &Ed25519KeyPair) -> H160 {
+        H160::hash(key.public_key().as_ref())
+    }
+
+    fn funded_state(outputs: &[Output]) -> (State, H256) {
+        let mut state = State::new();
+        let txid = generate_rand_hash256();
+        for (index, out) in outputs.iter().enumerate() {
+            state.map.insert(OutPoint{ txid, index: index as u32 }, out.clone());
+        }
+        (state, txid)
+    }
+
+    #[test]
+    fn multi_output() {
+        let mut state = State::new();
+        let tx = transaction(&key_pair::random(), vec![], vec![0, 0, 0]);
+        state.apply(&tx);
+        assert_eq!(state.map.len(), 3);
+        for index in 0..3 {
+            assert!(state.map.contains_key(&OutPoint{ txid: tx.hash(), index }));
+        }
+    }
+
+    #[test]
+    fn spend_with_change() {
+        let key = key_pair::random();
+        let address = address(&key);
+        let (mut state, txid) = funded_state(&[Output{balance: 7, address}, Output{balance: 5, address}]);
+        let tx = transaction(&key, vec![Input{index: 1, previous_hash: txid}], vec![3, 2]);
+        assert_eq!(state.input_val(&tx.transaction), Some(5));
+        assert_eq!(state.check(&tx), Ok(0));
+        state.apply(&tx);
+        assert!(state.map.contains_key(&OutPoint{ txid, index: 0 }));
+        assert!(!state.map.contains_key(&OutPoint{ txid, index: 1 }));
+        assert_eq!(state.map.get(&OutPoint{ txid: tx.hash(), index: 1 }).unwrap().balance, 2);
+    }
+
+    #[test]
+    fn value_comes_from_spent_output() {
+        let key = key_pair::random();
+        let address = address(&key);
+        let (state, txid) = funded_state(&[Output{balance: 7, address}, Output{balance: 5, address}]);
+        let tx = transaction(&key, vec![Input{index: 1, previous_hash: txid}], vec![6]);
+        assert_eq!(state.check(&tx), Err(TxError::Overspend));
+    }
+
+    #[test]
+    fn missing_output_index() {
+        let key = key_pair::random();
+        let (state, txid) = funded_state(&[Output{balance: 7, address: address(&key)}]);
+        let tx = transaction(&key, vec![Input{index: 1, previous_hash: txid}], vec![0]);
+        assert_eq!(state.check(&tx), Err(TxError::DoubleSpend));
+    }
+
+    #[test]
+    fn spend_someone_elses_output() {
+        let owner = key_pair::random();
+        let thief = key_pair::random();
+        let (state, txid) = funded_state(&[Output{balance: 7, address: address(&owner)}]);
+        let theft = transaction(&thief, vec![Input{index: 0, previous_hash: txid}], vec![7]);
+        assert_eq!(state.check(&theft), Err(TxError::NotOwner));
+        let spend = transaction(&owner, vec![Input{index: 0, previous_hash: txid}], vec![7]);
+        assert_eq!(state.check(&spend), Ok(0));
+    }
+
+    #[test]
+    fn reject_value_overflow() {
+        let key = key_pair::random();
+        let address = address(&key);
+        let half = Amount::MAX / 2 + 1;
+        let (state, txid) = funded_state(&[Output{balance: half, address}, Output{balance: half, address}]);
+        let inputs = vec![Input{index: 0, previous_hash: txid}, Input{index: 1, previous_hash: txid}];
+        let tx = transaction(&key, inputs, vec![1]);
+        assert_eq!(state.check(&tx), Err(TxError::ValueOverflow));
+        let tx = transaction(&key, vec![Input{index: 0, previous_hash: txid}], vec![Amount::MAX, 1]);
+        assert_eq!(state.check(&tx), Err(TxError::ValueOverflow));
+        let tx = transaction(&key, vec![Input{index: 0, previous_hash: txid}], vec![half - 1, 1]);
+        assert_eq!(state.check(&tx), Ok(0));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::convert::TryInto;
+use log::warn;
+use crate::block::Block;
+use crate::crypto::hash::{H256, U256, Hashable};
+
+/// Start of every record, to detect garbage at the end of a file
+const MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+/// Magic, payload length, checksum, height and cumulative work
+const RECORD_HEADER: usize = 48;
+/// A new file is started once the current one would grow past this size
+const MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Where a block is stored, with its place in the block tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockPos {
+    pub file: u32,
+    pub offset: u64,
+    pub height: u32,
+    pub work: U256,
+}
+
+/// Append-only block files `blkNNNNN.dat` in a data directory.
+/// Each record is the magic, the payload length, a checksum, the height and cumulative work of the block,
+/// then the payload, the block. The checksum covers everything after it. The index is rebuilt
+/// from the records when the files are scanned, a torn record at the end of a file is cut off.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    file: File, // blocks are appended to the last file
+    file_num: u32,
+    file_len: u64,
+    index: HashMap<H256, BlockPos>,
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    digest.as_ref()[..4].try_into().unwrap()
+}
+
+fn file_path(dir: &Path, num: u32) -> PathBuf {
+    dir.join(format!("blk{:05}.dat", num))
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Parse the record at the start of `data` into its length, the height and work of the block
+/// and the block. None if it is incomplete or corrupted.
+fn parse_record(data: &[u8]) -> Option<(usize, u32, U256, Block)> {
+    if data.len() < RECORD_HEADER || data[..4] != MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    let checked = data.get(12..RECORD_HEADER + len)?;
+    if data[8..12] != checksum(checked) {
+        return None;
+    }
+    let height = u32::from_le_bytes(data[12..16].try_into().unwrap());
+    let work: [u8; 32] = data[16..RECORD_HEADER].try_into().unwrap();
+    let block: Block = bincode::deserialize(&data[RECORD_HEADER..RECORD_HEADER + len]).ok()?;
+    Some((RECORD_HEADER + len, height, H256::from(work).into(), block))
+}
+
+impl BlockStore {
+    /// Open the block files in `dir`, creating it if needed. Returns the store and every stored block,
+    /// in the order they were written, so parents come before children.
+    pub fn open(dir: &Path) -> io::Result<(BlockStore, Vec<Block>)> {
+        fs::create_dir_all(dir)?;
+        let mut index = HashMap::new();
+        let mut blocks = Vec::new();
+        let mut num = 0;
+        let mut len = 0;
+        while file_path(dir, num).exists() {
+            len = Self::scan(dir, num, &mut index, &mut blocks)?;
+            if !file_path(dir, num + 1).exists() {
+                break;
+            }
+            num += 1;
+        }
+        let file = open_append(&file_path(dir, num))?;
+        Ok((BlockStore{ dir: dir.to_path_buf(), file, file_num: num, file_len: len, index }, blocks))
+    }
+
+    /// Index and read the blocks of one file and return its length, truncating it after the last good record
+    fn scan(dir: &Path, num: u32, index: &mut HashMap<H256, BlockPos>, blocks: &mut Vec<Block>) -> io::Result<u64> {
+        let path = file_path(dir, num);
+        let data = fs::read(&path)?;
+        let mut offset = 0;
+        while offset < data.len() {
+            let (len, height, work, block) = match parse_record(&data[offset..]) {
+                Some(record) => record,
+                None => {
+                    warn!("Truncating {:?} at {}: torn or corrupted record", path, offset);
+                    OpenOptions::new().write(true).open(&path)?.set_len(offset as u64)?;
+                    break;
+                }
+            };
+            index.insert(block.hash(), BlockPos{ file: num, offset: offset as u64, height, work });
+            blocks.push(block);
+            offset += len;
+        }
+        Ok(offset as u64)
+    }
+
+    /// Write a block at the end of the last file and wait until it is on disk
+    pub fn append(&mut self, block: &Block, height: u32, work: U256) -> io::Result<()> {
+        let hash = block.hash();
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+        let payload = bincode::serialize(block).unwrap();
+        let work_bytes: [u8; 32] = H256::from(work).into();
+        let mut checked = Vec::with_capacity(RECORD_HEADER - 12 + payload.len());
+        checked.extend_from_slice(&height.to_le_bytes());
+        checked.extend_from_slice(&work_bytes);
+        checked.extend_from_slice(&payload);
+        let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+        record.extend_from_slice(&MAGIC);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&checked));
+        record.extend_from_slice(&checked);
+
+        if self.file_len > 0 && self.file_len + record.len() as u64 > MAX_FILE_SIZE {
+            self.file = open_append(&file_path(&self.dir, self.file_num + 1))?;
+            self.file_num += 1;
+            self.file_len = 0;
+        }
+        if let Err(e) = self.file.write_all(&record).and_then(|_| self.file.sync_data()) {
+            // don't leave a partial record in front of the next one
+            let _ = self.file.set_len(self.file_len);
+            return Err(e);
+        }
+        self.index.insert(hash, BlockPos{ file: self.file_num, offset: self.file_len, height, work });
+        self.file_len += record.len() as u64;
+        Ok(())
+    }
+
+    pub fn get_pos(&self, hash: &H256) -> Option<&BlockPos> {
+        self.index.get(hash)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+pub mod tests {
+    use super::*;
+    use crate::block::generate_random_block;
+    use rand::Rng;
+
+    /// A fresh directory under the system temp dir
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bc-{}-{}", name, rand::thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chain(length: usize) -> Vec<Block> {
+        let mut blocks = vec![generate_random_block(&H256::default())];
+        for _ in 1..length {
+            let parent = blocks.last().unwrap().hash();
+            blocks.push(generate_random_block(&parent));
+        }
+        blocks
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<H256> {
+        blocks.iter().map(|block| block.hash()).collect()
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = temp_dir("store");
+        let blocks = chain(3);
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert!(stored.is_empty());
+        for (height, block) in blocks.iter().enumerate() {
+            store.append(block, height as u32 + 1, U256::from(height as u64)).unwrap();
+        }
+        // appending again is a no-op
+        store.append(&blocks[0], 1, U256::ZERO).unwrap();
+        let pos = store.get_pos(&blocks[2].hash()).unwrap().clone();
+        drop(store);
+
+        let (store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(hashes(&stored), hashes(&blocks));
+        assert_eq!(store.get_pos(&blocks[2].hash()), Some(&pos));
+        assert_eq!(pos.height, 3);
+        assert_eq!(pos.work, U256::from(2u64));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_write_is_truncated() {
+        let dir = temp_dir("store");
+        let blocks = chain(3);
+        let path = file_path(&dir, 0);
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        store.append(&blocks[0], 1, U256::ONE).unwrap();
+        let good_len = fs::metadata(&path).unwrap().len();
+        store.append(&blocks[1], 2, U256::ONE).unwrap();
+        drop(store);
+
+        // the last record only made it halfway to disk
+        let full_len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(full_len - 5).unwrap();
+
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(hashes(&stored), hashes(&blocks[..1]));
+        assert!(store.get_pos(&blocks[1].hash()).is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+        // writing goes on after the last good record
+        store.append(&blocks[1], 2, U256::ONE).unwrap();
+        store.append(&blocks[2], 3, U256::ONE).unwrap();
+        drop(store);
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(hashes(&stored), hashes(&blocks));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_record_is_truncated() {
+        let dir = temp_dir("store");
+        let blocks = chain(2);
+        let path = file_path(&dir, 0);
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        store.append(&blocks[0], 1, U256::ONE).unwrap();
+        let offset = fs::metadata(&path).unwrap().len();
+        store.append(&blocks[1], 2, U256::ONE).unwrap();
+        drop(store);
+
+        // flip a byte in the height of the second record, the checksum catches it
+        let mut data = fs::read(&path).unwrap();
+        data[offset as usize + 12] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), offset);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
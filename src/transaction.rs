@@ -4,15 +4,24 @@ use std::collections::HashSet;
 use ring::{digest, rand::SecureRandom, signature::Ed25519KeyPair};
 use crate::crypto::hash::{H256,H160,Hashable, generate_rand_hash256,generate_rand_hash160};
 
+/// Reference to one output of a transaction: (txid, output index)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: H256,
+    pub index: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Input {
-    pub index: u8,
+    pub index: u32, // position of the spent output in the previous transaction
     pub previous_hash: H256,
 }
 
 impl Input{
-    pub fn get_val(&self) -> u8 {self.clone().index}
     pub fn get_hash(&self) -> H256 {self.clone().previous_hash}
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint{ txid: self.previous_hash, index: self.index }
+    }
 }
 
 impl Hashable for Input {
@@ -28,14 +37,17 @@ impl Hashable for Input {
 
 
 
+/// Amount of coins, in the smallest unit (like satoshis)
+pub type Amount = u64;
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Output {
-    pub balance: u8,
+    pub balance: Amount,
     pub address: H160
 }
 
 impl Output{
-    pub fn get_val(&self) -> u8 {self.clone().balance }
+    pub fn get_val(&self) -> Amount {self.balance}
     pub fn get_address(&self) -> H160 {self.clone().address}
 }
 
@@ -54,30 +66,37 @@ impl Hashable for Output{
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    pub id: H256,
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>
 }
 
+/// The transaction id: hash of the transaction body, the signature is not included
+impl Hashable for Transaction {
+    fn hash(&self) -> H256 {
+        let serialized = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &serialized).into()
+    }
+}
+
 impl Transaction{
-    pub fn get_id(&self) -> H256{self.clone().id}
     pub fn get_input(&self) -> Vec<Input>{self.clone().inputs}
     pub fn get_output(&self) -> Vec<Output>{self.clone().outputs}
 
-    pub fn input_hash(&self) -> HashSet<H256>{
-        self.inputs.iter().map(|input|input.previous_hash).collect::<HashSet<H256>>()
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].index == COINBASE_INDEX
     }
 
-    pub fn output_address(&self) -> HashSet<H160>{
-        self.outputs.iter().map(|output|output.address).collect::<HashSet<H160>>()
+    pub fn input_outpoints(&self) -> HashSet<OutPoint>{
+        self.inputs.iter().map(|input|input.outpoint()).collect::<HashSet<OutPoint>>()
     }
 
-    pub fn input_val(&self) -> u8 {
-        self.inputs.iter().map(|input| input.index).sum()
+    pub fn output_address(&self) -> HashSet<H160>{
+        self.outputs.iter().map(|output|output.address).collect::<HashSet<H160>>()
     }
 
-    pub fn output_val(&self) -> u8 {
-        self.outputs.iter().map(|output| output.balance).sum()
+    /// Total value of the outputs, None if it overflows
+    pub fn output_val(&self) -> Option<Amount> {
+        self.outputs.iter().try_fold(0, |sum: Amount, output| sum.checked_add(output.balance))
     }
 }
 
@@ -97,13 +116,15 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     peer_public_key.verify(msg.as_ref(), signature.as_ref()).is_ok()
 }
 
-pub fn coin_base(address: &H160) -> Transaction{
-    use hex_literal::hex;
-    let hash = (hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
-    let input = Input{index: 0, previous_hash: hash};
-    let output = Output{ balance: 10,  address: address.clone()};
-    let t = Transaction{id:generate_rand_hash256(), inputs: vec![input], outputs: vec![output]};
-    t
+/// Output index marking the only input of a coinbase transaction
+pub const COINBASE_INDEX: u32 = u32::MAX;
+
+/// Create the coinbase transaction of a block mined on top of `parent`.
+/// Its input refers to the parent block, so every coinbase has a different txid.
+pub fn coin_base(address: &H160, parent: &H256, value: Amount) -> Transaction{
+    let input = Input{index: COINBASE_INDEX, previous_hash: *parent};
+    let output = Output{ balance: value,  address: *address};
+    Transaction{inputs: vec![input], outputs: vec![output]}
 }
 
 pub fn generate_random_transaction() -> Transaction {
@@ -112,13 +133,12 @@ pub fn generate_random_transaction() -> Transaction {
     let mut result = [0u8; 32];
     sr.fill(&mut result).unwrap();
     let hash:H256 = generate_rand_hash256();
-    let index:u8 = rng.gen();
+    let index:u32 = rng.gen();
     let inputs = Input{index, previous_hash:hash};
-    let val:u8 = rng.gen();
+    let val:Amount = rng.gen();
     let address = generate_rand_hash160();
     let outputs = Output{ balance: val, address};
-    let id = generate_rand_hash256();
-    let trans = Transaction{id, inputs:vec![inputs], outputs:vec![outputs] };
+    let trans = Transaction{inputs:vec![inputs], outputs:vec![outputs] };
     trans
 }
 
@@ -135,4 +155,17 @@ mod tests {
         let signature = sign(&t, &key);
         assert!(verify(&t, &(key.public_key().as_ref()), &signature));
     }
+
+    #[test]
+    fn txid_from_content() {
+        let t = generate_random_transaction();
+        let a = crate::signedtrans::SignedTrans::new(t.clone(), &key_pair::random());
+        let b = crate::signedtrans::SignedTrans::new(t.clone(), &key_pair::random());
+        assert_eq!(a.hash(), t.hash());
+        assert_eq!(a.hash(), b.hash());
+        assert_ne!(a.witness_hash(), b.witness_hash());
+        let mut other = t.clone();
+        other.outputs[0].balance ^= 1;
+        assert_ne!(other.hash(), t.hash());
+    }
 }
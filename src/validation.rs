@@ -0,0 +1,337 @@
+use std::time::SystemTime;
+use crate::block::{Block, Header, merkle_root};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::state::TxError;
+
+/// How far in the future (in milliseconds) a block timestamp may be
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Why a block is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// the header hash is above the target it claims
+    InvalidProofOfWork,
+    /// the target is easier than the proof of work limit of the chain
+    TargetAboveLimit,
+    /// the merkle root in the header doesn't commit to the block content
+    MerkleRootMismatch,
+    /// the serialized block is larger than the maximum block size
+    BlockTooLarge,
+    /// the timestamp is too far ahead of our clock
+    TimestampTooNew,
+    /// the parent is not known yet, the block is an orphan
+    UnknownParent,
+    /// the target differs from the one the retarget rule expects after the parent
+    WrongDifficulty,
+    /// the timestamp is earlier than the parent's
+    TimestampTooOld,
+    /// the first transaction is not a coinbase
+    MissingCoinbase,
+    /// the coinbase doesn't refer to the parent block
+    BadCoinbase,
+    /// a coinbase appears after the first transaction
+    MultipleCoinbase,
+    /// the coinbase claims more than the subsidy plus the fees of the block
+    CoinbaseTooLarge,
+    /// a transaction (identified by its hash) can't be executed on the state before it
+    InvalidTransaction(H256, TxError),
+    /// the block couldn't be written to the block store, it is left out of the chain
+    Storage(String),
+}
+
+impl BlockError {
+    /// Whether the block breaks a consensus rule and never can be valid, rather than being
+    /// too early, ahead of its parent or failing on our side
+    pub fn is_invalid(&self) -> bool {
+        !matches!(self, BlockError::UnknownParent | BlockError::TimestampTooNew | BlockError::Storage(_))
+    }
+}
+
+/// Run every check on a block before it is inserted into the blockchain.
+/// Context-free checks come first, so an orphan has at least a valid PoW.
+pub fn validate_block(bc: &Blockchain, block: &Block) -> Result<(), BlockError> {
+    let header = &block.header;
+
+    // PoW validity check
+    if block.hash() > header.difficulty {
+        return Err(BlockError::InvalidProofOfWork);
+    }
+    // the parent's target isn't known for an orphan, the limit still keeps cheap blocks out of the pool
+    if header.difficulty > bc.params.pow_limit {
+        return Err(BlockError::TargetAboveLimit);
+    }
+
+    if merkle_root(&block.content) != header.get_merkle_root() {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+
+    if block.size() > bc.params.max_block_size {
+        return Err(BlockError::BlockTooLarge);
+    }
+
+    check_timestamp(header)?;
+
+    // Parent check
+    let parent = match bc.blocks.get(&header.parent) {
+        Some((parent, _, _)) => &parent.header,
+        None => return Err(BlockError::UnknownParent),
+    };
+    check_header_context(bc, header, parent)?;
+
+    // coinbase, signatures, double spends and overspends, in block order
+    bc.connect_block(block).map(|_| ())
+}
+
+/// Run the checks of a block that only need its header, before its body is downloaded.
+/// The parent header must be known, its block doesn't have to be.
+pub fn validate_header(bc: &Blockchain, header: &Header) -> Result<(), BlockError> {
+    if header.hash() > header.difficulty {
+        return Err(BlockError::InvalidProofOfWork);
+    }
+
+    check_timestamp(header)?;
+
+    let parent = match bc.headers.get(&header.parent) {
+        Some((parent, _, _)) => parent,
+        None => return Err(BlockError::UnknownParent),
+    };
+    check_header_context(bc, header, parent)
+}
+
+fn check_timestamp(header: &Header) -> Result<(), BlockError> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+    if header.get_create_time() > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimestampTooNew);
+    }
+    Ok(())
+}
+
+/// Checks of a header against its parent
+fn check_header_context(bc: &Blockchain, header: &Header, parent: &Header) -> Result<(), BlockError> {
+    if header.difficulty != bc.next_difficulty(&header.parent) {
+        return Err(BlockError::WrongDifficulty);
+    }
+
+    if header.get_create_time() < parent.get_create_time() {
+        return Err(BlockError::TimestampTooOld);
+    }
+    Ok(())
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_mined_block, mine, set_content, set_timestamp};
+    use crate::crypto::hash::{H160, generate_rand_hash256};
+    use crate::crypto::key_pair;
+    use crate::signedtrans::{SignedTrans, generate_random_signedtrans};
+    use crate::transaction::{Amount, Input, Output, Transaction, coin_base};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn genesis(bc: &Blockchain) -> Block {
+        bc.blocks.get(&bc.tip()).unwrap().0.clone()
+    }
+
+    /// A transaction signed by `key` that pays back to the address of `key`
+    fn transaction(key: &Ed25519KeyPair, inputs: Vec<Input>, balance: Amount) -> SignedTrans {
+        let t = Transaction{
+            inputs,
+            outputs: vec![Output{balance, address: H160::hash(key.public_key().as_ref())}],
+        };
+        SignedTrans::new(t, key)
+    }
+
+    /// Mine a block whose coinbase pays 10 to `key`, returns it with an input spending that output
+    fn funded(bc: &mut Blockchain, key: &Ed25519KeyPair) -> (Block, Input) {
+        let genesis = genesis(bc);
+        let coinbase = coin_base(&H160::hash(key.public_key().as_ref()), &genesis.hash(), 10);
+        let coinbase = SignedTrans::coinbase(coinbase);
+        let block = generate_mined_block(&genesis, vec![coinbase.clone()]);
+        bc.insert(&block).unwrap();
+        (block, Input{index: 0, previous_hash: coinbase.hash()})
+    }
+
+    /// Change the header and search a nonce again, so only the intended check fails
+    fn remine(block: &Block, change: impl Fn(&mut Block)) -> Block {
+        let mut block = block.clone();
+        change(&mut block);
+        mine(&mut block);
+        block
+    }
+
+    #[test]
+    fn valid_block() {
+        let mut bc = Blockchain::new();
+        let key = key_pair::random();
+        let (parent, input) = funded(&mut bc, &key);
+        let block = generate_mined_block(&parent, vec![transaction(&key, vec![input], 10)]);
+        assert_eq!(validate_block(&bc, &block), Ok(()));
+    }
+
+    #[test]
+    fn invalid_proof_of_work() {
+        let bc = Blockchain::new();
+        let mut block = generate_mined_block(&genesis(&bc), vec![]);
+        while block.hash() <= block.header.difficulty {
+            block.header.parent = generate_rand_hash256();
+        }
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn target_above_limit() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let block = remine(&block, |b| {
+            b.header.parent = generate_rand_hash256();
+            b.header.difficulty = [0xff; 32].into();
+        });
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::TargetAboveLimit));
+    }
+
+    #[test]
+    fn merkle_root_mismatch() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let block = remine(&block, |b| b.content.push(generate_random_signedtrans()));
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::MerkleRootMismatch));
+    }
+
+    #[test]
+    fn block_too_large() {
+        let mut bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        bc.params.max_block_size = block.size() - 1;
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::BlockTooLarge));
+        bc.params.max_block_size = block.size();
+        assert_eq!(validate_block(&bc, &block), Ok(()));
+    }
+
+    #[test]
+    fn timestamp_too_new() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let block = remine(&block, |b| set_timestamp(b, b.header.get_create_time() + 2 * MAX_FUTURE_BLOCK_TIME));
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::TimestampTooNew));
+    }
+
+    #[test]
+    fn unknown_parent() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let block = remine(&block, |b| b.header.parent = generate_rand_hash256());
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::UnknownParent));
+    }
+
+    #[test]
+    fn wrong_difficulty() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let mut easier = [0u8; 32];
+        easier[1] = 32;
+        let block = remine(&block, |b| b.header.difficulty = easier.into());
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::WrongDifficulty));
+    }
+
+    #[test]
+    fn timestamp_too_old() {
+        let mut bc = Blockchain::new();
+        let parent = generate_mined_block(&genesis(&bc), vec![]);
+        bc.insert(&parent).unwrap();
+        let block = generate_mined_block(&parent, vec![]);
+        let block = remine(&block, |b| set_timestamp(b, parent.header.get_create_time() - 1));
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::TimestampTooOld));
+    }
+
+    #[test]
+    fn invalid_signature() {
+        let mut bc = Blockchain::new();
+        let key = key_pair::random();
+        let (parent, input) = funded(&mut bc, &key);
+        let mut tx = transaction(&key, vec![input], 10);
+        tx.signature = generate_random_signedtrans().signature;
+        let block = generate_mined_block(&parent, vec![tx.clone()]);
+        assert_eq!(validate_block(&bc, &block),
+                   Err(BlockError::InvalidTransaction(tx.hash(), TxError::InvalidSignature)));
+    }
+
+    #[test]
+    fn double_spend() {
+        let mut bc = Blockchain::new();
+        let key = key_pair::random();
+        let (parent, input) = funded(&mut bc, &key);
+        let spend = transaction(&key, vec![input.clone()], 10);
+        // spend the same output again, with a fee so both have a different txid
+        let respend = transaction(&key, vec![input], 9);
+        let block = generate_mined_block(&parent, vec![spend, respend.clone()]);
+        assert_eq!(validate_block(&bc, &block),
+                   Err(BlockError::InvalidTransaction(respend.hash(), TxError::DoubleSpend)));
+    }
+
+    #[test]
+    fn duplicate_input() {
+        let mut bc = Blockchain::new();
+        let key = key_pair::random();
+        let (parent, input) = funded(&mut bc, &key);
+        let spend = transaction(&key, vec![input.clone(), input], 10);
+        let block = generate_mined_block(&parent, vec![spend.clone()]);
+        assert_eq!(validate_block(&bc, &block),
+                   Err(BlockError::InvalidTransaction(spend.hash(), TxError::DuplicateInput)));
+    }
+
+    #[test]
+    fn overspend() {
+        let mut bc = Blockchain::new();
+        let key = key_pair::random();
+        let (parent, input) = funded(&mut bc, &key);
+        let tx = transaction(&key, vec![input], 11);
+        let block = generate_mined_block(&parent, vec![tx.clone()]);
+        assert_eq!(validate_block(&bc, &block),
+                   Err(BlockError::InvalidTransaction(tx.hash(), TxError::Overspend)));
+    }
+
+    #[test]
+    fn spend_someone_elses_output() {
+        let mut bc = Blockchain::new();
+        let owner = key_pair::random();
+        let thief = key_pair::random();
+        let (parent, input) = funded(&mut bc, &owner);
+        let theft = transaction(&thief, vec![input], 10);
+        let block = generate_mined_block(&parent, vec![theft.clone()]);
+        assert_eq!(validate_block(&bc, &block),
+                   Err(BlockError::InvalidTransaction(theft.hash(), TxError::NotOwner)));
+    }
+
+    #[test]
+    fn missing_coinbase() {
+        let bc = Blockchain::new();
+        let block = generate_mined_block(&genesis(&bc), vec![]);
+        let block = remine(&block, |b| set_content(b, vec![]));
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::MissingCoinbase));
+    }
+
+    #[test]
+    fn coinbase_too_large() {
+        let bc = Blockchain::new();
+        let genesis = genesis(&bc);
+        let value = bc.params.subsidy(1) + 1;
+        let coinbase = SignedTrans::coinbase(coin_base(&H160::default(), &genesis.hash(), value));
+        let block = generate_mined_block(&genesis, vec![coinbase]);
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::CoinbaseTooLarge));
+    }
+
+    #[test]
+    fn header_without_block() {
+        let mut bc = Blockchain::new();
+        let parent = generate_mined_block(&genesis(&bc), vec![]);
+        let block = generate_mined_block(&parent, vec![]);
+        assert_eq!(validate_header(&bc, &block.header), Err(BlockError::UnknownParent));
+        bc.insert_header(&parent.header).unwrap();
+        // the header is checked against its parent header, the parent block isn't needed
+        assert_eq!(validate_header(&bc, &block.header), Ok(()));
+        assert_eq!(validate_block(&bc, &block), Err(BlockError::UnknownParent));
+        let late = remine(&block, |b| set_timestamp(b, parent.header.get_create_time() - 1));
+        assert_eq!(validate_header(&bc, &late.header), Err(BlockError::TimestampTooOld));
+    }
+}